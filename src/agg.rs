@@ -1,10 +1,59 @@
+use std::sync::Arc;
+
 use tantivy::{Result, SegmentLocalId, SegmentReader, DocId, Score, Searcher};
 use tantivy::query::Scorer;
 
+use crate::parent_docs::ParentDocs;
+
 pub struct AggSegmentContext<'r, 's> {
     pub segment_ord: SegmentLocalId,
     pub reader: &'r SegmentReader,
     pub scorer: &'s dyn Scorer,
+    pub(crate) top_level: bool,
+    pub(crate) parent_docs: Option<Arc<ParentDocs>>,
+}
+
+impl<'r, 's> AggSegmentContext<'r, 's> {
+    pub fn new(segment_ord: SegmentLocalId, reader: &'r SegmentReader, scorer: &'s dyn Scorer) -> Self {
+        Self {
+            segment_ord,
+            reader,
+            scorer,
+            top_level: true,
+            parent_docs: None,
+        }
+    }
+
+    /// Whether the aggregation is not nested into another one, tuples are not counted
+    pub(crate) fn is_top_level(&self) -> bool {
+        self.top_level
+    }
+
+    /// Context of the aggregations nested into the current one
+    pub(crate) fn sub_agg_ctx(&self) -> Self {
+        Self {
+            segment_ord: self.segment_ord,
+            reader: self.reader,
+            scorer: self.scorer,
+            top_level: false,
+            parent_docs: self.parent_docs.clone(),
+        }
+    }
+
+    /// Parent documents of the innermost nested aggregation
+    pub(crate) fn parent_docs(&self) -> Option<&Arc<ParentDocs>> {
+        self.parent_docs.as_ref()
+    }
+
+    pub(crate) fn with_parent_docs(&self, parent_docs: Option<Arc<ParentDocs>>) -> Self {
+        Self {
+            segment_ord: self.segment_ord,
+            reader: self.reader,
+            scorer: self.scorer,
+            top_level: false,
+            parent_docs,
+        }
+    }
 }
 
 pub trait Agg {
@@ -25,6 +74,20 @@ pub trait PreparedAgg: Sync {
     fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child>;
 
     fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit);
+
+    /// Called once after fruits of all segments were merged
+    fn finalize(&self, _fruit: &mut Self::Fruit) -> Result<()> {
+        Ok(())
+    }
+
+    /// Finalizes fruits of all the buckets of a parent aggregation,
+    /// aggregations making a pass over the index override it to make a single pass
+    fn finalize_buckets(&self, fruits: &mut [&mut Self::Fruit]) -> Result<()> {
+        for fruit in fruits.iter_mut() {
+            self.finalize(fruit)?;
+        }
+        Ok(())
+    }
 }
 
 pub trait SegmentAgg {
@@ -33,4 +96,8 @@ pub trait SegmentAgg {
     fn create_fruit(&self) -> Self::Fruit;
 
     fn collect(&mut self, doc: DocId, score: Score, output: &mut Self::Fruit);
+
+    /// Called once after all documents of the segment were collected.
+    /// Fruits of a segment are finished before they are merged with fruits of other segments.
+    fn finish(&mut self, _output: &mut Self::Fruit) {}
 }
\ No newline at end of file
@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, HashSet};
+
+use tantivy::{DocId, Result, Score, Searcher, TantivyError};
+use tantivy::query::{Query, Weight};
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::filter::FilterMatcher;
+
+const DEFAULT_SEPARATOR: &str = "&";
+
+/// Buckets documents by the named filters and by every pair of the filters.
+///
+/// Bucket of a pair is named as `A&B` where `A` is the name of the filter
+/// that goes first in the `filters`. Only buckets with documents are returned.
+/// Filter names must be unique and must not contain the separator.
+pub fn adjacency_matrix_agg<'q, SubAgg>(
+    filters: Vec<(&str, &'q dyn Query)>, sub_agg: SubAgg
+) -> AdjacencyMatrixAgg<'q, SubAgg>
+where
+    SubAgg: Agg,
+{
+    AdjacencyMatrixAgg {
+        filters: filters.into_iter()
+            .map(|(name, query)| (name.to_string(), query))
+            .collect(),
+        separator: DEFAULT_SEPARATOR.to_string(),
+        sub_agg,
+    }
+}
+
+pub struct AdjacencyMatrixAgg<'q, SubAgg>
+where
+    SubAgg: Agg,
+{
+    filters: Vec<(String, &'q dyn Query)>,
+    separator: String,
+    sub_agg: SubAgg,
+}
+
+impl<'q, SubAgg> AdjacencyMatrixAgg<'q, SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Separator of the filter names in the keys of intersection buckets
+    pub fn separator(mut self, separator: &str) -> Self {
+        self.separator = separator.to_string();
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.separator.is_empty() {
+            return Err(TantivyError::InvalidArgument(
+                "Separator of the filter names must not be empty".to_string()
+            ));
+        }
+        let mut names = HashSet::new();
+        for (name, _) in &self.filters {
+            if name.contains(&self.separator) {
+                return Err(TantivyError::InvalidArgument(format!(
+                    "Filter name must not contain the separator {:?}: {}", self.separator, name
+                )));
+            }
+            if !names.insert(name.as_str()) {
+                return Err(TantivyError::InvalidArgument(
+                    format!("Duplicate filter name: {}", name)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'q, SubAgg> Agg for AdjacencyMatrixAgg<'q, SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = AdjacencyMatrix<SubAgg::Fruit>;
+    type Child = PreparedAdjacencyMatrixAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        self.validate()?;
+        let weights = self.filters.iter()
+            .map(|(_, query)| query.weight(searcher, false))
+            .collect::<Result<Vec<_>>>()?;
+        let keys = self.filters.iter().enumerate()
+            .map(|(i, (name, _))| {
+                self.filters.iter().enumerate()
+                    .map(|(j, (other_name, _))| {
+                        if i == j {
+                            name.clone()
+                        } else if i < j {
+                            format!("{}{}{}", name, self.separator, other_name)
+                        } else {
+                            format!("{}{}{}", other_name, self.separator, name)
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(Self::Child {
+            weights,
+            keys,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedAdjacencyMatrixAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    weights: Vec<Box<dyn Weight>>,
+    keys: Vec<Vec<String>>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedAdjacencyMatrixAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = AdjacencyMatrix<SubAgg::Fruit>;
+    type Child = AdjacencyMatrixSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        AdjacencyMatrix::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let matchers = self.weights.iter()
+            .map(|weight| FilterMatcher::new(weight.as_ref(), ctx.reader))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::Child {
+            matchers,
+            keys: self.keys.clone(),
+            matched: vec!(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        for (key, bucket) in fruit.buckets {
+            let existing_bucket = harvest.buckets.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+
+            self.sub_agg.merge(existing_bucket, bucket);
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.buckets.values_mut().collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+pub struct AdjacencyMatrixSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    matchers: Vec<FilterMatcher>,
+    keys: Vec<Vec<String>>,
+    matched: Vec<usize>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> AdjacencyMatrixSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    fn collect_bucket(
+        &mut self, key: (usize, usize), doc: DocId, score: Score, fruit: &mut AdjacencyMatrix<SubAgg::Fruit>
+    ) {
+        let key = &self.keys[key.0][key.1];
+        if !fruit.buckets.contains_key(key) {
+            fruit.buckets.insert(key.clone(), self.sub_agg.create_fruit());
+        }
+        let bucket = fruit.buckets.get_mut(key).unwrap();
+        self.sub_agg.collect(doc, score, bucket);
+    }
+}
+
+impl<SubAgg> SegmentAgg for AdjacencyMatrixSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = AdjacencyMatrix<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        AdjacencyMatrix::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        // Every matcher must see all the documents to keep up with them
+        self.matched.clear();
+        for (ix, matcher) in self.matchers.iter_mut().enumerate() {
+            if matcher.matches(doc) {
+                self.matched.push(ix);
+            }
+        }
+
+        for i in 0..self.matched.len() {
+            let filter_ix = self.matched[i];
+            self.collect_bucket((filter_ix, filter_ix), doc, score, fruit);
+            for j in (i + 1)..self.matched.len() {
+                let other_filter_ix = self.matched[j];
+                self.collect_bucket((filter_ix, other_filter_ix), doc, score, fruit);
+            }
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        for bucket in fruit.buckets.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AdjacencyMatrix<T> {
+    buckets: BTreeMap<String, T>,
+}
+
+impl<T> AdjacencyMatrix<T> {
+    fn new() -> Self {
+        Self {
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&T> {
+        self.buckets.get(key)
+    }
+
+    /// Non-empty buckets ordered by key
+    pub fn buckets(&self) -> Vec<(&str, &T)> {
+        self.buckets.iter()
+            .map(|(key, bucket)| (key.as_str(), bucket))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::{Result, Term};
+    use tantivy::query::{AllQuery, RangeQuery, TermQuery};
+    use tantivy::schema::IndexRecordOption;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use super::adjacency_matrix_agg;
+
+    #[test]
+    fn test_adjacency_matrix_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let acme_query = TermQuery::new(
+            Term::from_field_text(product_index.schema.brand, "acme"),
+            IndexRecordOption::Basic
+        );
+        let cat_2_query = product_index.category_query(2);
+        let cheap_query = RangeQuery::new_f64(product_index.schema.price, 0_f64..10_f64);
+        let agg = adjacency_matrix_agg(
+            vec!(
+                ("acme", &acme_query),
+                ("cat_2", &cat_2_query),
+                ("cheap", &cheap_query),
+            ),
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let matrix = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            matrix.buckets(),
+            vec!(
+                ("acme", &(3_u64, Some(9.99_f64))),
+                ("acme&cat_2", &(1_u64, Some(50.0_f64))),
+                ("acme&cheap", &(1_u64, Some(9.99_f64))),
+                ("cat_2", &(3_u64, Some(0.5_f64))),
+                ("cat_2&cheap", &(1_u64, Some(0.5_f64))),
+                ("cheap", &(2_u64, Some(0.5_f64))),
+            )
+        );
+
+        let matrix = searcher.agg_search(
+            &product_index.category_query(1),
+            &adjacency_matrix_agg(
+                vec!(("cheap", &cheap_query), ("acme", &acme_query)),
+                count_agg()
+            ).separator(" and ")
+        )?;
+        assert_eq!(
+            matrix.buckets(),
+            vec!(
+                ("acme", &2_u64),
+                ("cheap", &1_u64),
+                ("cheap and acme", &1_u64),
+            )
+        );
+        assert_eq!(matrix.get("cat_2"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjacency_matrix_agg_invalid_names() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let cat_1_query = product_index.category_query(1);
+        let cat_2_query = product_index.category_query(2);
+        assert!(
+            searcher.agg_search(
+                &AllQuery,
+                &adjacency_matrix_agg(
+                    vec!(("cat", &cat_1_query), ("cat", &cat_2_query)), count_agg()
+                )
+            ).is_err()
+        );
+        assert!(
+            searcher.agg_search(
+                &AllQuery,
+                &adjacency_matrix_agg(
+                    vec!(("cat&1", &cat_1_query), ("cat_2", &cat_2_query)), count_agg()
+                )
+            ).is_err()
+        );
+        assert!(
+            searcher.agg_search(
+                &AllQuery,
+                &adjacency_matrix_agg(
+                    vec!(("cat&1", &cat_1_query), ("cat&2", &cat_2_query)), count_agg()
+                ).separator(" and ")
+            ).is_ok()
+        );
+        assert!(
+            searcher.agg_search(
+                &AllQuery,
+                &adjacency_matrix_agg(
+                    vec!(("cat_1", &cat_1_query), ("cat_2", &cat_2_query)), count_agg()
+                ).separator("")
+            ).is_err()
+        );
+
+        Ok(())
+    }
+}
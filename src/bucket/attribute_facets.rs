@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+
+use tantivy::{DocId, Result, Score, Searcher, TantivyError};
+use tantivy::fastfield::{FastFieldNotAvailableError, MultiValueIntFastFieldReader};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use super::terms::Terms;
+
+const DEFAULT_VALUE_BITS: u32 = 32;
+const MAX_VALUE_BITS: u32 = 63;
+
+/// Buckets documents by attributes and their values packed as `(attr_id << 32) | value_id`
+/// into a multi-valued fast field, other splits are set with `value_bits`.
+///
+/// Values are not pruned while collecting, the buckets of all the values of every attribute
+/// are kept in memory and [`AttributeFacets::top_k`] picks from them after the search.
+pub fn attribute_facets_agg<SubAgg>(field: Field, sub_agg: SubAgg) -> AttributeFacetsAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    AttributeFacetsAgg {
+        field,
+        value_bits: DEFAULT_VALUE_BITS,
+        sub_agg,
+    }
+}
+
+pub struct AttributeFacetsAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    value_bits: u32,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> AttributeFacetsAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Number of the lower bits holding the value id, from 0 to 63
+    pub fn value_bits(mut self, value_bits: u32) -> Self {
+        self.value_bits = value_bits;
+        self
+    }
+}
+
+impl<SubAgg> Agg for AttributeFacetsAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = AttributeFacets<SubAgg::Fruit>;
+    type Child = PreparedAttributeFacetsAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        if self.value_bits > MAX_VALUE_BITS {
+            return Err(TantivyError::InvalidArgument(format!(
+                "Value bits must be from 0 to {}: {}", MAX_VALUE_BITS, self.value_bits
+            )));
+        }
+        Ok(Self::Child {
+            field: self.field,
+            value_bits: self.value_bits,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedAttributeFacetsAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    field: Field,
+    value_bits: u32,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedAttributeFacetsAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = AttributeFacets<SubAgg::Fruit>;
+    type Child = AttributeFacetsSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        AttributeFacets::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let ff_reader = ctx.reader.fast_fields().u64s(self.field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(self.field)
+                )
+            })?;
+        Ok(Self::Child {
+            ff_reader,
+            value_bits: self.value_bits,
+            vals: vec!(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        for (attr_id, values) in fruit.attrs {
+            let existing_values = harvest.attrs.entry(attr_id)
+                .or_insert_with(Terms::new);
+            for (value_id, bucket) in values.res {
+                let existing_bucket = existing_values.res.entry(value_id)
+                    .or_insert_with(|| self.sub_agg.create_fruit());
+
+                self.sub_agg.merge(existing_bucket, bucket);
+            }
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(
+            &mut harvest.attrs.values_mut().flat_map(|values| values.res.values_mut()).collect::<Vec<_>>()
+        )?;
+        Ok(())
+    }
+}
+
+pub struct AttributeFacetsSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    ff_reader: MultiValueIntFastFieldReader<u64>,
+    value_bits: u32,
+    vals: Vec<u64>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for AttributeFacetsSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = AttributeFacets<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        AttributeFacets::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        self.ff_reader.get_vals(doc, &mut self.vals);
+        let value_mask = (1_u64 << self.value_bits) - 1;
+        for &packed in self.vals.iter() {
+            let attr_id = packed >> self.value_bits;
+            let value_id = packed & value_mask;
+            let bucket = fruit.attrs.entry(attr_id)
+                .or_insert_with(Terms::new)
+                .res.entry(value_id)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        for values in fruit.attrs.values_mut() {
+            for bucket in values.res.values_mut() {
+                self.sub_agg.finish(bucket);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AttributeFacets<T> {
+    attrs: HashMap<u64, Terms<u64, T>>,
+}
+
+impl<T> AttributeFacets<T> {
+    fn new() -> Self {
+        Self {
+            attrs: HashMap::new(),
+        }
+    }
+
+    /// Values of the attribute
+    pub fn get(&self, attr_id: u64) -> Option<&Terms<u64, T>> {
+        self.attrs.get(&attr_id)
+    }
+
+    /// Attributes ordered by id
+    pub fn attributes(&self) -> Vec<(u64, &Terms<u64, T>)> {
+        let mut attrs = self.attrs.iter()
+            .map(|(&attr_id, values)| (attr_id, values))
+            .collect::<Vec<_>>();
+        attrs.sort_by_key(|(attr_id, _)| *attr_id);
+        attrs
+    }
+
+    /// Top `k` values of the attribute, see [`Terms::top_k`]
+    pub fn top_k<'a, F, U>(&'a self, attr_id: u64, k: usize, sort_by: F) -> Vec<(&'a u64, &'a T)>
+    where
+        F: FnMut(&'a T) -> U,
+        U: Copy + Ord,
+    {
+        self.attrs.get(&attr_id)
+            .map_or_else(Vec::new, |values| values.top_k(k, sort_by))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use super::attribute_facets_agg;
+
+    #[test]
+    fn test_attribute_facets_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = attribute_facets_agg(
+            product_index.schema.attr_facets,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let attrs = searcher.agg_search(&AllQuery, &agg)?;
+        let attr_ids = attrs.attributes().into_iter()
+            .map(|(attr_id, _)| attr_id)
+            .collect::<Vec<_>>();
+        assert_eq!(attr_ids, vec!(1, 2));
+        assert_eq!(
+            attrs.get(1).map(|values| values.buckets()),
+            Some(vec!(
+                (&1_u64, &(1_u64, Some(9.99_f64))),
+                (&2_u64, &(1_u64, Some(10_f64))),
+            ))
+        );
+        assert_eq!(
+            attrs.top_k(2, 10, |b| b.0),
+            vec!((&3_u64, &(2_u64, Some(9.99_f64))))
+        );
+        assert_eq!(attrs.top_k(3, 10, |b| b.0), vec!());
+
+        let attrs = searcher.agg_search(
+            &AllQuery,
+            &attribute_facets_agg(product_index.schema.attr_facets, count_agg()).value_bits(34)
+        )?;
+        assert_eq!(attrs.attributes().len(), 1);
+        assert_eq!(
+            attrs.top_k(0, 1, |count| *count),
+            vec!((&((2_u64 << 32) | 3), &2_u64))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_attribute_facets_agg_invalid_value_bits() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let field = product_index.schema.attr_facets;
+        assert!(searcher.agg_search(&AllQuery, &attribute_facets_agg(field, count_agg()).value_bits(64)).is_err());
+        assert!(searcher.agg_search(&AllQuery, &attribute_facets_agg(field, count_agg()).value_bits(63)).is_ok());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use tantivy::{DocId, Result, Score, Searcher, SegmentReader};
+use tantivy::fastfield::{FastFieldNotAvailableError, FastFieldReader};
+use tantivy::query::{AllWeight, Weight};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::missing::PresenceReader;
+
+/// Aggregates child documents linked to the matched parent documents.
+///
+/// Ids of the parents are read from the `parent_id_field`, children are the documents
+/// whose `child_link_field` is equal to one of the ids. Both are single-valued fast fields,
+/// so documents without them get the `0` id unless presence fields are set.
+///
+/// Children are collected in a second pass over all the segments once ids of the parents are known,
+/// nested into a bucket aggregation a single pass routes children to all the buckets.
+pub fn children_agg<SubAgg>(
+    parent_id_field: Field, child_link_field: Field, sub_agg: SubAgg
+) -> ChildrenAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    ChildrenAgg {
+        parent_id_field,
+        child_link_field,
+        parent_presence: None,
+        child_presence: None,
+        sub_agg,
+    }
+}
+
+pub struct ChildrenAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    parent_id_field: Field,
+    child_link_field: Field,
+    parent_presence: Option<Field>,
+    child_presence: Option<Field>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> ChildrenAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Skips parent documents without a value in the `presence_field`.
+    /// The presence field must be a multi-valued fast field.
+    pub fn parent_presence(mut self, presence_field: Field) -> Self {
+        self.parent_presence = Some(presence_field);
+        self
+    }
+
+    /// Skips child documents without a value in the `presence_field`.
+    /// The presence field must be a multi-valued fast field.
+    pub fn child_presence(mut self, presence_field: Field) -> Self {
+        self.child_presence = Some(presence_field);
+        self
+    }
+}
+
+impl<SubAgg> Agg for ChildrenAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = Children<SubAgg::Fruit>;
+    type Child = PreparedChildrenAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            parent_id_field: self.parent_id_field,
+            child_link_field: self.child_link_field,
+            parent_presence: self.parent_presence,
+            child_presence: self.child_presence,
+            segment_readers: searcher.segment_readers().to_vec(),
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedChildrenAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    parent_id_field: Field,
+    child_link_field: Field,
+    parent_presence: Option<Field>,
+    child_presence: Option<Field>,
+    segment_readers: Vec<SegmentReader>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedChildrenAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Children<SubAgg::Fruit>;
+    type Child = ChildrenSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Children::new(self.sub_agg.create_fruit())
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            ff_reader: u64_reader(ctx.reader, self.parent_id_field)?,
+            presence: open_presence(ctx.reader, self.parent_presence)?,
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
+        acc.parent_ids.extend(fruit.parent_ids);
+        self.sub_agg.merge(&mut acc.sub_agg, fruit.sub_agg);
+    }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        self.finalize_buckets(&mut [fruit])
+    }
+
+    fn finalize_buckets(&self, fruits: &mut [&mut Self::Fruit]) -> Result<()> {
+        // Buckets the children of a parent are collected into
+        let mut buckets_by_parent_id: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (ix, fruit) in fruits.iter_mut().enumerate() {
+            for parent_id in mem::take(&mut fruit.parent_ids) {
+                buckets_by_parent_id.entry(parent_id).or_default().push(ix);
+            }
+        }
+        if !buckets_by_parent_id.is_empty() {
+            for (segment_ord, reader) in self.segment_readers.iter().enumerate() {
+                let link_reader = u64_reader(reader, self.child_link_field)?;
+                let mut presence = open_presence(reader, self.child_presence)?;
+                let scorer = AllWeight.scorer(reader)?;
+                let ctx = AggSegmentContext::new(segment_ord as u32, reader, scorer.as_ref());
+                let mut segment_agg = self.sub_agg.for_segment(&ctx.sub_agg_ctx())?;
+                // Like in the searcher every segment is collected into its own fruits,
+                // which are finished and then merged into the buckets
+                let mut segment_fruits: Vec<Option<SubAgg::Fruit>> = fruits.iter().map(|_| None).collect();
+                for doc in 0..reader.max_doc() {
+                    if reader.is_deleted(doc) || is_missing(&mut presence, doc) {
+                        continue;
+                    }
+                    if let Some(buckets) = buckets_by_parent_id.get(&link_reader.get(doc)) {
+                        for &ix in buckets {
+                            let segment_fruit = segment_fruits[ix]
+                                .get_or_insert_with(|| self.sub_agg.create_fruit());
+                            segment_agg.collect(doc, 1.0, segment_fruit);
+                        }
+                    }
+                }
+                for (fruit, segment_fruit) in fruits.iter_mut().zip(segment_fruits) {
+                    if let Some(mut segment_fruit) = segment_fruit {
+                        segment_agg.finish(&mut segment_fruit);
+                        self.sub_agg.merge(&mut fruit.sub_agg, segment_fruit);
+                    }
+                }
+            }
+        }
+        self.sub_agg.finalize_buckets(
+            &mut fruits.iter_mut().map(|fruit| &mut fruit.sub_agg).collect::<Vec<_>>()
+        )
+    }
+}
+
+pub struct ChildrenSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    ff_reader: FastFieldReader<u64>,
+    presence: Option<PresenceReader>,
+    /// Only creates fruits, children are collected in the second pass
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for ChildrenSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Children<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Children::new(self.sub_agg.create_fruit())
+    }
+
+    fn collect(&mut self, doc: DocId, _: Score, fruit: &mut Self::Fruit) {
+        if !is_missing(&mut self.presence, doc) {
+            fruit.parent_ids.insert(self.ff_reader.get(doc));
+        }
+    }
+}
+
+fn u64_reader(reader: &SegmentReader, field: Field) -> Result<FastFieldReader<u64>> {
+    Ok(
+        reader.fast_fields().u64_lenient(field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(reader.schema().get_field_entry(field))
+            })?
+    )
+}
+
+fn open_presence(reader: &SegmentReader, presence_field: Option<Field>) -> Result<Option<PresenceReader>> {
+    match presence_field {
+        Some(presence_field) => Ok(Some(PresenceReader::for_reader(reader, presence_field)?)),
+        None => Ok(None),
+    }
+}
+
+fn is_missing(presence: &mut Option<PresenceReader>, doc: DocId) -> bool {
+    presence.as_mut().map_or(false, |presence| presence.is_missing(doc))
+}
+
+#[derive(Debug)]
+pub struct Children<T> {
+    parent_ids: HashSet<u64>,
+    sub_agg: T,
+}
+
+impl<T> Children<T> {
+    fn new(sub_agg: T) -> Self {
+        Self {
+            parent_ids: HashSet::new(),
+            sub_agg,
+        }
+    }
+
+    pub fn sub_agg(&self) -> &T {
+        &self.sub_agg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::{Result, Term};
+    use tantivy::query::{RangeQuery, TermQuery};
+    use tantivy::schema::IndexRecordOption;
+
+    use test_fixtures::ProductIndex;
+
+    use tantivy::query::AllQuery;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64, terms_agg_str, terms_agg_u64};
+    use super::children_agg;
+
+    #[test]
+    fn test_children_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = children_agg(
+            product_index.schema.id,
+            product_index.schema.product_id,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let offers = searcher.agg_search(&product_index.category_query(2), &agg)?;
+        assert_eq!(offers.sub_agg(), &(3_u64, Some(45_f64)));
+
+        let globex_query = TermQuery::new(
+            Term::from_field_text(product_index.schema.brand, "globex"),
+            IndexRecordOption::Basic
+        );
+        let offers = searcher.agg_search(&globex_query, &agg)?;
+        assert_eq!(offers.sub_agg(), &(0_u64, None));
+
+        let cat_offers = searcher.agg_search(
+            &RangeQuery::new_u64(product_index.schema.id, 1..100),
+            &terms_agg_u64(
+                product_index.schema.category_id,
+                children_agg(product_index.schema.id, product_index.schema.product_id, count_agg())
+            )
+        )?;
+        assert_eq!(cat_offers.get(&1).map(|offers| *offers.sub_agg()), Some(4_u64));
+        assert_eq!(cat_offers.get(&2).map(|offers| *offers.sub_agg()), Some(3_u64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_children_agg_presence() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        // Offers have the `0` id and products have the `0` link without the presence fields
+        let agg = children_agg(
+            product_index.schema.id, product_index.schema.product_id, count_agg()
+        );
+        assert_eq!(searcher.agg_search(&AllQuery, &agg)?.sub_agg(), &12_u64);
+
+        let agg = agg
+            .parent_presence(product_index.schema.id_presence)
+            .child_presence(product_index.schema.product_id_presence);
+        assert_eq!(searcher.agg_search(&AllQuery, &agg)?.sub_agg(), &7_u64);
+
+        let cat_offers = searcher.agg_search(
+            &AllQuery,
+            &terms_agg_u64(
+                product_index.schema.category_id,
+                children_agg(product_index.schema.id, product_index.schema.product_id, count_agg())
+                    .parent_presence(product_index.schema.id_presence)
+                    .child_presence(product_index.schema.product_id_presence)
+            )
+        )?;
+        assert_eq!(cat_offers.get(&1).map(|offers| *offers.sub_agg()), Some(4_u64));
+        assert_eq!(cat_offers.get(&2).map(|offers| *offers.sub_agg()), Some(3_u64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_children_agg_multiple_segments() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        product_index.index_offers(1, vec!((1, 9.29), (1, 9.39), (3, 9.89)))?;
+        let searcher = product_index.reader.searcher();
+        assert_eq!(searcher.segment_readers().len(), 2);
+
+        // Offers of the shops are in both segments
+        let shop_offers = searcher.agg_search(
+            &RangeQuery::new_u64(product_index.schema.id, 1..100),
+            &children_agg(
+                product_index.schema.id,
+                product_index.schema.product_id,
+                terms_agg_str(product_index.schema.shop_name, count_agg())
+            )
+        )?;
+        let shops = shop_offers.sub_agg();
+        assert_eq!(shops.get("bestbuy"), Some(&5_u64));
+        assert_eq!(shops.get("walmart"), Some(&2_u64));
+        assert_eq!(shops.get("target"), Some(&3_u64));
+        assert_eq!(shops.doc_count_error_upper_bound(), 0);
+
+        Ok(())
+    }
+}
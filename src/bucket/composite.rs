@@ -0,0 +1,540 @@
+use std::collections::BTreeMap;
+
+use tantivy::{DateTime, DocId, Result, Score, Searcher, TantivyError};
+use tantivy::chrono::{Duration, NaiveDateTime, Utc};
+use tantivy::fastfield::{FastFieldNotAvailableError, FastFieldReader};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::missing::PresenceReader;
+use super::multi_terms::{KeyReader, KeySource};
+
+const DEFAULT_SIZE: usize = 10;
+
+/// Histogram source for composite aggregation.
+/// The key is a bucket number, multiply it by the interval to get the bucket start.
+/// `NaN` values are skipped.
+pub fn histogram_source_f64(field: Field, interval: f64) -> HistogramSourceF64 {
+    HistogramSourceF64 { field, interval, presence: None }
+}
+
+#[derive(Clone, Copy)]
+pub struct HistogramSourceF64 {
+    field: Field,
+    interval: f64,
+    presence: Option<Field>,
+}
+
+impl HistogramSourceF64 {
+    /// Skips documents without a value in the `presence_field`.
+    /// The presence field must be a multi-valued fast field.
+    pub fn presence(mut self, presence_field: Field) -> Self {
+        self.presence = Some(presence_field);
+        self
+    }
+}
+
+impl KeySource for HistogramSourceF64 {
+    type Key = i64;
+    type Reader = HistogramKeyReaderF64;
+
+    fn reader(&self, ctx: &AggSegmentContext) -> Result<Self::Reader> {
+        let ff_reader = ctx.reader.fast_fields().f64(self.field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(self.field)
+                )
+            })?;
+        Ok(HistogramKeyReaderF64 {
+            ff_reader,
+            presence: open_presence(ctx, self.presence)?,
+            interval: self.interval,
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(self.interval.is_finite() && self.interval > 0.0) {
+            return Err(TantivyError::InvalidArgument(
+                format!("Histogram interval must be positive: {}", self.interval)
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct HistogramKeyReaderF64 {
+    ff_reader: FastFieldReader<f64>,
+    presence: Option<PresenceReader>,
+    interval: f64,
+}
+
+impl KeyReader for HistogramKeyReaderF64 {
+    type Key = i64;
+
+    fn read(&mut self, doc: DocId, keys: &mut Vec<Self::Key>) {
+        keys.clear();
+        if is_missing(&mut self.presence, doc) {
+            return;
+        }
+        let value = self.ff_reader.get(doc);
+        if !value.is_nan() {
+            keys.push((value / self.interval).floor() as i64);
+        }
+    }
+}
+
+/// Date histogram source for composite aggregation with fixed intervals
+/// of at least one second. The key is a start of the bucket.
+pub fn date_histogram_source(field: Field, interval: Duration) -> DateHistogramSource {
+    DateHistogramSource { field, interval, presence: None }
+}
+
+#[derive(Clone, Copy)]
+pub struct DateHistogramSource {
+    field: Field,
+    interval: Duration,
+    presence: Option<Field>,
+}
+
+impl DateHistogramSource {
+    /// Skips documents without a value in the `presence_field`.
+    /// The presence field must be a multi-valued fast field.
+    pub fn presence(mut self, presence_field: Field) -> Self {
+        self.presence = Some(presence_field);
+        self
+    }
+}
+
+impl KeySource for DateHistogramSource {
+    type Key = DateTime;
+    type Reader = DateHistogramKeyReader;
+
+    fn reader(&self, ctx: &AggSegmentContext) -> Result<Self::Reader> {
+        let ff_reader = ctx.reader.fast_fields().date(self.field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(self.field)
+                )
+            })?;
+        Ok(DateHistogramKeyReader {
+            ff_reader,
+            presence: open_presence(ctx, self.presence)?,
+            interval_secs: self.interval.num_seconds(),
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.interval.num_seconds() < 1 {
+            return Err(TantivyError::InvalidArgument(
+                format!("Date histogram interval must be at least one second: {:?}", self.interval)
+            ));
+        }
+        if self.interval != Duration::seconds(self.interval.num_seconds()) {
+            return Err(TantivyError::InvalidArgument(
+                format!("Date histogram interval must be a whole number of seconds: {:?}", self.interval)
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct DateHistogramKeyReader {
+    ff_reader: FastFieldReader<DateTime>,
+    presence: Option<PresenceReader>,
+    interval_secs: i64,
+}
+
+impl KeyReader for DateHistogramKeyReader {
+    type Key = DateTime;
+
+    fn read(&mut self, doc: DocId, keys: &mut Vec<Self::Key>) {
+        keys.clear();
+        if is_missing(&mut self.presence, doc) {
+            return;
+        }
+        let timestamp = self.ff_reader.get(doc).timestamp();
+        let bucket_timestamp = timestamp.div_euclid(self.interval_secs) * self.interval_secs;
+        keys.push(DateTime::from_utc(NaiveDateTime::from_timestamp(bucket_timestamp, 0), Utc));
+    }
+}
+
+fn open_presence(ctx: &AggSegmentContext, presence_field: Option<Field>) -> Result<Option<PresenceReader>> {
+    match presence_field {
+        Some(presence_field) => Ok(Some(PresenceReader::open(ctx, presence_field)?)),
+        None => Ok(None),
+    }
+}
+
+fn is_missing(presence: &mut Option<PresenceReader>, doc: DocId) -> bool {
+    presence.as_mut().map_or(false, |presence| presence.is_missing(doc))
+}
+
+/// Returns buckets ordered by their keys, `size` buckets at a time.
+///
+/// Pass the `after_key` of the result into `after` to fetch the next page.
+/// Only the `size` lowest keys are kept while collecting, so memory
+/// does not depend on the number of unique keys.
+pub fn composite_agg<Sources, SubAgg>(
+    sources: Sources, sub_agg: SubAgg
+) -> CompositeAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    CompositeAgg {
+        sources,
+        size: DEFAULT_SIZE,
+        after: None,
+        sub_agg,
+    }
+}
+
+pub struct CompositeAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    sources: Sources,
+    size: usize,
+    after: Option<Sources::Key>,
+    sub_agg: SubAgg,
+}
+
+impl<Sources, SubAgg> CompositeAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    /// Maximum number of buckets, defaults to 10
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Collects only keys that are greater than the `after_key`
+    pub fn after(mut self, after_key: Sources::Key) -> Self {
+        self.after = Some(after_key);
+        self
+    }
+}
+
+impl<Sources, SubAgg> Agg for CompositeAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    type Fruit = Composite<Sources::Key, SubAgg::Fruit>;
+    type Child = PreparedCompositeAgg<Sources, SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        self.sources.validate()?;
+        Ok(Self::Child {
+            sources: self.sources.clone(),
+            bounds: CompositeBounds {
+                size: self.size,
+                after: self.after,
+            },
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedCompositeAgg<Sources, SubAgg>
+where
+    Sources: KeySource,
+    SubAgg: PreparedAgg,
+{
+    sources: Sources,
+    bounds: CompositeBounds<Sources::Key>,
+    sub_agg: SubAgg,
+}
+
+impl<Sources, SubAgg> PreparedAgg for PreparedCompositeAgg<Sources, SubAgg>
+where
+    Sources: KeySource,
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Composite<Sources::Key, SubAgg::Fruit>;
+    type Child = CompositeSegmentAgg<Sources::Reader, SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Composite::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            reader: self.sources.reader(ctx)?,
+            bounds: self.bounds,
+            keys: vec!(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        for (key, bucket) in fruit.buckets {
+            let existing_bucket = harvest.buckets.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+
+            self.sub_agg.merge(existing_bucket, bucket);
+        }
+        while harvest.buckets.len() > self.bounds.size {
+            harvest.pop_last();
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.buckets.values_mut().collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CompositeBounds<K> {
+    size: usize,
+    after: Option<K>,
+}
+
+pub struct CompositeSegmentAgg<Reader, SubAgg>
+where
+    Reader: KeyReader,
+    SubAgg: SegmentAgg,
+{
+    reader: Reader,
+    bounds: CompositeBounds<Reader::Key>,
+    keys: Vec<Reader::Key>,
+    sub_agg: SubAgg,
+}
+
+impl<Reader, SubAgg> SegmentAgg for CompositeSegmentAgg<Reader, SubAgg>
+where
+    Reader: KeyReader,
+    Reader::Key: Copy + Ord,
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Composite<Reader::Key, SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Composite::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
+        if self.bounds.size == 0 {
+            return;
+        }
+        self.reader.read(doc, &mut self.keys);
+        for &key in self.keys.iter() {
+            if self.bounds.after.map_or(false, |after| key <= after) {
+                continue;
+            }
+            if agg_value.buckets.len() >= self.bounds.size &&
+                !agg_value.buckets.contains_key(&key)
+            {
+                // Evicted keys are greater than the last key so they are never collected again
+                let last_key = agg_value.buckets.keys().next_back().copied();
+                match last_key {
+                    Some(last_key) if key < last_key => agg_value.pop_last(),
+                    _ => continue,
+                }
+            }
+            let bucket = agg_value.buckets.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
+        }
+    }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for bucket in agg_value.buckets.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Composite<K, T> {
+    buckets: BTreeMap<K, T>,
+}
+
+impl<K, T> Composite<K, T>
+where
+    K: Copy + Ord,
+{
+    fn new() -> Self {
+        Self {
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    fn pop_last(&mut self) {
+        if let Some(&last_key) = self.buckets.keys().next_back() {
+            self.buckets.remove(&last_key);
+        }
+    }
+
+    /// Buckets ordered by key
+    pub fn buckets(&self) -> Vec<(&K, &T)> {
+        self.buckets.iter().collect()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&T> {
+        self.buckets.get(key)
+    }
+
+    /// Key of the last bucket, pass it to `after` to fetch the next page.
+    /// It is `None` only for an empty page, a page with less than `size` buckets is the last one too.
+    pub fn after_key(&self) -> Option<K> {
+        self.buckets.keys().next_back().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::chrono::{DateTime, Duration, Utc};
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, terms_source_u64, terms_source_u64s};
+    use super::{composite_agg, date_histogram_source, histogram_source_f64};
+
+    #[test]
+    fn test_composite_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let sources = (
+            terms_source_u64(product_index.schema.category_id),
+            terms_source_u64s(product_index.schema.tag_ids),
+        );
+        let agg = composite_agg(sources, count_agg()).size(3);
+        let page = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            page.buckets(),
+            vec!(
+                (&(1_u64, 111_u64), &2_u64),
+                (&(1_u64, 112_u64), &1_u64),
+                (&(1_u64, 211_u64), &2_u64),
+            )
+        );
+        assert_eq!(page.after_key(), Some((1_u64, 211_u64)));
+
+        let agg = composite_agg(sources, count_agg()).size(3).after((1, 211));
+        let page = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            page.buckets(),
+            vec!(
+                (&(1_u64, 320_u64), &1_u64),
+                (&(2_u64, 211_u64), &1_u64),
+                (&(2_u64, 311_u64), &2_u64),
+            )
+        );
+
+        let agg = composite_agg(sources, count_agg()).size(3).after((2, 311));
+        let page = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            page.buckets(),
+            vec!(
+                (&(2_u64, 320_u64), &1_u64),
+                (&(2_u64, 511_u64), &1_u64),
+            )
+        );
+
+        let agg = composite_agg(sources, count_agg()).size(3).after((2, 511));
+        let page = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(page.buckets(), vec!());
+        assert_eq!(page.after_key(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_composite_agg_histogram_sources() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = composite_agg(
+            histogram_source_f64(product_index.schema.price, 10.0),
+            count_agg()
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (&0_i64, &2_u64),
+                (&1_i64, &1_u64),
+                (&5_i64, &1_u64),
+                (&10_i64, &1_u64),
+            )
+        );
+
+        let agg = composite_agg(
+            date_histogram_source(product_index.schema.date_created, Duration::days(1))
+                .presence(product_index.schema.date_created_presence),
+            count_agg()
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (
+                    &DateTime::parse_from_rfc3339("2019-12-31T00:00:00+00:00").unwrap().with_timezone(&Utc),
+                    &2_u64
+                ),
+                (
+                    &DateTime::parse_from_rfc3339("2020-01-01T00:00:00+00:00").unwrap().with_timezone(&Utc),
+                    &2_u64
+                ),
+            )
+        );
+
+        let agg = composite_agg(
+            date_histogram_source(product_index.schema.date_created, Duration::milliseconds(500)),
+            count_agg()
+        );
+        assert!(searcher.agg_search(&AllQuery, &agg).is_err());
+
+        let agg = composite_agg(
+            date_histogram_source(product_index.schema.date_created, Duration::milliseconds(1500)),
+            count_agg()
+        );
+        assert!(searcher.agg_search(&AllQuery, &agg).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_composite_agg_histogram_source_presence() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        // Offers have neither tags nor location
+        let agg = composite_agg(
+            histogram_source_f64(product_index.schema.lat, 30.0)
+                .presence(product_index.schema.tag_ids),
+            count_agg()
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (&0_i64, &1_u64),
+                (&1_i64, &4_u64),
+            )
+        );
+
+        let agg = composite_agg(histogram_source_f64(product_index.schema.lat, 30.0), count_agg());
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (&0_i64, &8_u64),
+                (&1_i64, &4_u64),
+            )
+        );
+
+        Ok(())
+    }
+}
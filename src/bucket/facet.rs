@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, HashMap};
+
+use tantivy::{DocId, Result, Score, Searcher, TantivyError};
+use tantivy::fastfield::FacetReader;
+use tantivy::schema::{Facet, Field};
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+
+/// Buckets documents by the facet paths under the `root` path.
+///
+/// Every node up to `depth` levels below the root gets its own bucket,
+/// so a document with the `/electronics/phones/smartphones` facet
+/// gets into the `/electronics/phones` and `/electronics/phones/smartphones`
+/// buckets when the root is `/electronics` and the depth is 2.
+pub fn facet_agg<SubAgg>(
+    field: Field, root: &str, depth: usize, sub_agg: SubAgg
+) -> FacetAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    FacetAgg {
+        field,
+        root: root.to_string(),
+        depth,
+        sub_agg,
+    }
+}
+
+pub struct FacetAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    root: String,
+    depth: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> Agg for FacetAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = Facets<SubAgg::Fruit>;
+    type Child = PreparedFacetAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        if !self.root.starts_with('/') {
+            return Err(TantivyError::InvalidArgument(
+                format!("Facet path must start with a slash: {}", self.root)
+            ));
+        }
+        Ok(Self::Child {
+            field: self.field,
+            root_path: facet_path(&Facet::from_text(&self.root)),
+            depth: self.depth,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedFacetAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    field: Field,
+    root_path: Vec<String>,
+    depth: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedFacetAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Facets<SubAgg::Fruit>;
+    type Child = FacetSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Facets::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            facet_reader: ctx.reader.facet_reader(self.field)?,
+            nodes: FacetNodes::new(self.root_path.clone(), self.depth),
+            ords: vec!(),
+            doc_nodes: vec!(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        for (facet, bucket) in fruit.buckets {
+            let existing_bucket = harvest.buckets.entry(facet)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+
+            self.sub_agg.merge(existing_bucket, bucket);
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.buckets.values_mut().collect::<Vec<_>>())?;
+        harvest.index_children();
+        Ok(())
+    }
+}
+
+pub struct FacetSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    facet_reader: FacetReader,
+    nodes: FacetNodes,
+    ords: Vec<u64>,
+    doc_nodes: Vec<usize>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for FacetSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Facets<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Facets::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        self.facet_reader.facet_ords(doc, &mut self.ords);
+        self.doc_nodes.clear();
+        for &ord in self.ords.iter() {
+            self.doc_nodes.extend_from_slice(
+                self.nodes.for_ord(&mut self.facet_reader, ord)
+            );
+        }
+        // Several facets of a document can share the same parent node
+        self.doc_nodes.sort_unstable();
+        self.doc_nodes.dedup();
+
+        let sub_agg = &mut self.sub_agg;
+        for &node in self.doc_nodes.iter() {
+            let bucket = fruit.node_buckets.entry(node)
+                .or_insert_with(|| sub_agg.create_fruit());
+            sub_agg.collect(doc, score, bucket);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        // Nodes are unique inside of a segment and the fruit has no buckets of other segments
+        for (node, bucket) in fruit.node_buckets.drain() {
+            fruit.buckets.insert(self.nodes.facet(node).clone(), bucket);
+        }
+        for bucket in fruit.buckets.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+/// Maps segment facet ordinals to the nodes they contribute to
+struct FacetNodes {
+    root_path: Vec<String>,
+    depth: usize,
+    facets: Vec<Facet>,
+    facet_nodes: HashMap<Facet, usize>,
+    ord_nodes: HashMap<u64, Vec<usize>>,
+    facet: Facet,
+}
+
+impl FacetNodes {
+    fn new(root_path: Vec<String>, depth: usize) -> Self {
+        Self {
+            root_path,
+            depth,
+            facets: vec!(),
+            facet_nodes: HashMap::new(),
+            ord_nodes: HashMap::new(),
+            facet: Facet::root(),
+        }
+    }
+
+    fn facet(&self, node: usize) -> &Facet {
+        &self.facets[node]
+    }
+
+    fn for_ord(&mut self, facet_reader: &mut FacetReader, ord: u64) -> &[usize] {
+        if !self.ord_nodes.contains_key(&ord) {
+            facet_reader.facet_from_ord(ord, &mut self.facet);
+            let path = facet_path(&self.facet);
+            let mut nodes = vec!();
+            if path.len() > self.root_path.len() && path.starts_with(&self.root_path) {
+                let max_len = path.len().min(self.root_path.len() + self.depth);
+                for len in (self.root_path.len() + 1)..=max_len {
+                    let node_facet = Facet::from_path(&path[..len]);
+                    let node = match self.facet_nodes.get(&node_facet) {
+                        Some(&node) => node,
+                        None => {
+                            self.facets.push(node_facet.clone());
+                            self.facet_nodes.insert(node_facet, self.facets.len() - 1);
+                            self.facets.len() - 1
+                        }
+                    };
+                    nodes.push(node);
+                }
+            }
+            self.ord_nodes.insert(ord, nodes);
+        }
+        &self.ord_nodes[&ord]
+    }
+}
+
+fn facet_path(facet: &Facet) -> Vec<String> {
+    facet.to_path().into_iter()
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_string())
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Facets<T> {
+    buckets: BTreeMap<Facet, T>,
+    /// Buckets of a segment keyed by the facet nodes
+    node_buckets: HashMap<usize, T>,
+    /// Children of the facets ordered by path
+    children: HashMap<Facet, Vec<Facet>>,
+}
+
+impl<T> Facets<T> {
+    fn new() -> Self {
+        Self {
+            buckets: BTreeMap::new(),
+            node_buckets: HashMap::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    fn index_children(&mut self) {
+        self.children.clear();
+        for facet in self.buckets.keys() {
+            let path = facet_path(facet);
+            let parent = Facet::from_path(&path[..path.len() - 1]);
+            self.children.entry(parent)
+                .or_default()
+                .push(facet.clone());
+        }
+    }
+
+    pub fn get(&self, facet: &Facet) -> Option<&T> {
+        self.buckets.get(facet)
+    }
+
+    /// Direct children of the facet ordered by path
+    pub fn children(&self, facet: &Facet) -> Vec<(&Facet, &T)> {
+        self.children.get(facet)
+            .map_or_else(Vec::new, |children| {
+                children.iter()
+                    .map(|child| (child, &self.buckets[child]))
+                    .collect()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::Facet;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use super::facet_agg;
+
+    #[test]
+    fn test_facet_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let top_categories = searcher.agg_search(
+            &AllQuery,
+            &facet_agg(product_index.schema.category_path, "/", 1, count_agg())
+        )?;
+        assert_eq!(
+            top_categories.children(&Facet::root()),
+            vec!(
+                (&Facet::from("/electronics"), &3_u64),
+                (&Facet::from("/home"), &1_u64),
+            )
+        );
+        assert_eq!(top_categories.get(&Facet::from("/electronics/phones")), None);
+        assert_eq!(top_categories.children(&Facet::from("/electronics")), vec!());
+
+        let res = searcher.agg_search(
+            &AllQuery,
+            &facet_agg(product_index.schema.category_path, "electronics", 1, count_agg())
+        );
+        assert!(res.is_err());
+
+        let electronics = searcher.agg_search(
+            &AllQuery,
+            &facet_agg(
+                product_index.schema.category_path,
+                "/electronics",
+                2,
+                (count_agg(), min_agg_f64(product_index.schema.price))
+            )
+        )?;
+        assert_eq!(electronics.get(&Facet::from("/electronics")), None);
+        assert_eq!(
+            electronics.children(&Facet::from("/electronics")),
+            vec!(
+                (&Facet::from("/electronics/audio"), &(2_u64, Some(0.5_f64))),
+                (&Facet::from("/electronics/phones"), &(2_u64, Some(9.99_f64))),
+            )
+        );
+        assert_eq!(
+            electronics.children(&Facet::from("/electronics/phones")),
+            vec!(
+                (&Facet::from("/electronics/phones/accessories"), &(1_u64, Some(10.0_f64))),
+                (&Facet::from("/electronics/phones/smartphones"), &(1_u64, Some(9.99_f64))),
+            )
+        );
+        assert_eq!(
+            electronics.get(&Facet::from("/electronics/audio/headphones")),
+            Some(&(1_u64, Some(0.5_f64)))
+        );
+
+        Ok(())
+    }
+}
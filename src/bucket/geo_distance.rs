@@ -0,0 +1,293 @@
+use tantivy::{DocId, Result, Score, Searcher};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::geo::{DistanceUnit, GeoPoint, GeoPointReader};
+
+/// Range of distances, `from` is inclusive and `to` is exclusive
+pub type DistanceRange = (Option<f64>, Option<f64>);
+
+/// Buckets documents by the distance from the `origin` into rings defined by the `ranges`.
+///
+/// Distances of the ranges are in the `unit`, documents get into every range they fall into.
+/// Latitude and longitude are single-valued fast fields, use `presence` to skip documents
+/// without a location.
+pub fn geo_distance_agg<SubAgg>(
+    lat_field: Field,
+    lon_field: Field,
+    origin: GeoPoint,
+    unit: DistanceUnit,
+    ranges: Vec<DistanceRange>,
+    sub_agg: SubAgg,
+) -> GeoDistanceAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    GeoDistanceAgg {
+        lat_field,
+        lon_field,
+        presence: None,
+        origin,
+        unit,
+        ranges,
+        sub_agg,
+    }
+}
+
+pub struct GeoDistanceAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    lat_field: Field,
+    lon_field: Field,
+    presence: Option<Field>,
+    origin: GeoPoint,
+    unit: DistanceUnit,
+    ranges: Vec<DistanceRange>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> GeoDistanceAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Skips documents without a value in the `presence_field`.
+    /// The presence field must be a multi-valued fast field.
+    pub fn presence(mut self, presence_field: Field) -> Self {
+        self.presence = Some(presence_field);
+        self
+    }
+}
+
+impl<SubAgg> Agg for GeoDistanceAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = GeoDistance<SubAgg::Fruit>;
+    type Child = PreparedGeoDistanceAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        let unit = self.unit;
+        let ranges_in_meters = self.ranges.iter()
+            .map(|&(from, to)| (from.map(|d| unit.to_meters(d)), to.map(|d| unit.to_meters(d))))
+            .collect();
+        Ok(Self::Child {
+            lat_field: self.lat_field,
+            lon_field: self.lon_field,
+            presence: self.presence,
+            origin: self.origin,
+            ranges: self.ranges.clone(),
+            ranges_in_meters,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedGeoDistanceAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    lat_field: Field,
+    lon_field: Field,
+    presence: Option<Field>,
+    origin: GeoPoint,
+    ranges: Vec<DistanceRange>,
+    ranges_in_meters: Vec<DistanceRange>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedGeoDistanceAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = GeoDistance<SubAgg::Fruit>;
+    type Child = GeoDistanceSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        GeoDistance {
+            buckets: self.ranges.iter()
+                .map(|&range| (range, self.sub_agg.create_fruit()))
+                .collect(),
+        }
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            points: GeoPointReader::open(ctx, self.lat_field, self.lon_field, self.presence)?,
+            origin: self.origin,
+            ranges: self.ranges.clone(),
+            ranges_in_meters: self.ranges_in_meters.clone(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        for (existing_bucket, (_, bucket)) in harvest.buckets.iter_mut().zip(fruit.buckets) {
+            self.sub_agg.merge(&mut existing_bucket.1, bucket);
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.buckets.iter_mut().map(|(_, bucket)| bucket).collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+pub struct GeoDistanceSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    points: GeoPointReader,
+    origin: GeoPoint,
+    ranges: Vec<DistanceRange>,
+    ranges_in_meters: Vec<DistanceRange>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for GeoDistanceSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = GeoDistance<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        GeoDistance {
+            buckets: self.ranges.iter()
+                .map(|&range| (range, self.sub_agg.create_fruit()))
+                .collect(),
+        }
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        let distance = match self.points.get(doc) {
+            Some(point) => self.origin.distance(&point),
+            None => return,
+        };
+        for (&(from, to), (_, bucket)) in self.ranges_in_meters.iter().zip(fruit.buckets.iter_mut()) {
+            if from.map_or(true, |from| distance >= from) && to.map_or(true, |to| distance < to) {
+                self.sub_agg.collect(doc, score, bucket);
+            }
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        for (_, bucket) in fruit.buckets.iter_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GeoDistance<T> {
+    buckets: Vec<(DistanceRange, T)>,
+}
+
+impl<T> GeoDistance<T> {
+    /// Buckets in the order of the ranges
+    pub fn buckets(&self) -> Vec<(&DistanceRange, &T)> {
+        self.buckets.iter()
+            .map(|(range, bucket)| (range, bucket))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use crate::geo::{DistanceUnit, GeoPoint};
+    use super::geo_distance_agg;
+
+    #[test]
+    fn test_geo_distance_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = geo_distance_agg(
+            product_index.schema.lat,
+            product_index.schema.lon,
+            GeoPoint::new(52.52, 13.405),
+            DistanceUnit::Kilometers,
+            vec!(
+                (None, Some(100.0)),
+                (Some(100.0), Some(1000.0)),
+                (Some(1000.0), None),
+                (Some(20_000.0), None),
+            ),
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let rings = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            rings.buckets(),
+            vec!(
+                (&(None, Some(100.0)), &(2_u64, Some(9.99_f64))),
+                (&(Some(100.0), Some(1000.0)), &(1_u64, Some(0.5_f64))),
+                (&(Some(1000.0), None), &(2_u64, Some(50.0_f64))),
+                (&(Some(20_000.0), None), &(0_u64, None)),
+            )
+        );
+
+        let agg = geo_distance_agg(
+            product_index.schema.lat,
+            product_index.schema.lon,
+            GeoPoint::new(21.3, -157.85),
+            DistanceUnit::Miles,
+            vec!((None, Some(10.0)), (None, Some(6000.0))),
+            count_agg()
+        );
+        let rings = searcher.agg_search(&product_index.category_query(2), &agg)?;
+        assert_eq!(
+            rings.buckets(),
+            vec!(
+                (&(None, Some(10.0)), &1_u64),
+                (&(None, Some(6000.0)), &2_u64),
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geo_distance_agg_presence() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        // Offers have no location, so they are at the null island without the presence field
+        let agg = geo_distance_agg(
+            product_index.schema.lat,
+            product_index.schema.lon,
+            GeoPoint::new(0.0, 0.0),
+            DistanceUnit::Kilometers,
+            vec!((None, Some(100.0)), (Some(100.0), None)),
+            count_agg()
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (&(None, Some(100.0)), &7_u64),
+                (&(Some(100.0), None), &5_u64),
+            )
+        );
+
+        let agg = agg.presence(product_index.schema.location_presence);
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (&(None, Some(100.0)), &0_u64),
+                (&(Some(100.0), None), &5_u64),
+            )
+        );
+
+        Ok(())
+    }
+}
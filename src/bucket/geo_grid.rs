@@ -0,0 +1,378 @@
+use std::hash::Hash;
+
+use tantivy::{DocId, Result, Score, Searcher, TantivyError};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::geo::{GeoBoundingBox, GeoPoint, GeoPointReader, GeoTile, MAX_GEOHASH_PRECISION, encode_geohash};
+use super::terms::Terms;
+
+/// Splits the surface of the Earth into cells
+pub trait GeoGrid: Copy + Sync {
+    type Key: Clone + Default + Eq + Hash + Ord + Send;
+
+    /// Replaces `key` with the key of the cell containing the point
+    fn cell(&self, point: GeoPoint, key: &mut Self::Key);
+
+    fn validate(&self) -> Result<()>;
+}
+
+#[derive(Clone, Copy)]
+pub struct GeohashGrid {
+    precision: usize,
+}
+
+impl GeoGrid for GeohashGrid {
+    type Key = String;
+
+    fn cell(&self, point: GeoPoint, key: &mut Self::Key) {
+        encode_geohash(point, self.precision, key);
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.precision < 1 || self.precision > MAX_GEOHASH_PRECISION {
+            return Err(TantivyError::InvalidArgument(format!(
+                "Geohash precision must be from 1 to {}: {}", MAX_GEOHASH_PRECISION, self.precision
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct GeotileGrid {
+    zoom: u8,
+}
+
+impl GeoGrid for GeotileGrid {
+    type Key = GeoTile;
+
+    fn cell(&self, point: GeoPoint, key: &mut Self::Key) {
+        *key = GeoTile::from_point(point, self.zoom);
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.zoom > GeoTile::MAX_ZOOM {
+            return Err(TantivyError::InvalidArgument(format!(
+                "Geotile zoom must be at most {}: {}", GeoTile::MAX_ZOOM, self.zoom
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Buckets documents by geohash cells, the precision is from 1 to 12 characters
+pub fn geohash_grid_agg<SubAgg>(
+    lat_field: Field, lon_field: Field, precision: usize, sub_agg: SubAgg
+) -> GeoGridAgg<GeohashGrid, SubAgg>
+where
+    SubAgg: Agg,
+{
+    GeoGridAgg {
+        lat_field,
+        lon_field,
+        presence: None,
+        grid: GeohashGrid { precision },
+        bounds: None,
+        sub_agg,
+    }
+}
+
+/// Buckets documents by web mercator map tiles of the `zoom` level, the zoom is at most 29
+pub fn geotile_grid_agg<SubAgg>(
+    lat_field: Field, lon_field: Field, zoom: u8, sub_agg: SubAgg
+) -> GeoGridAgg<GeotileGrid, SubAgg>
+where
+    SubAgg: Agg,
+{
+    GeoGridAgg {
+        lat_field,
+        lon_field,
+        presence: None,
+        grid: GeotileGrid { zoom },
+        bounds: None,
+        sub_agg,
+    }
+}
+
+pub struct GeoGridAgg<Grid, SubAgg>
+where
+    Grid: GeoGrid,
+    SubAgg: Agg,
+{
+    lat_field: Field,
+    lon_field: Field,
+    presence: Option<Field>,
+    grid: Grid,
+    bounds: Option<GeoBoundingBox>,
+    sub_agg: SubAgg,
+}
+
+impl<Grid, SubAgg> GeoGridAgg<Grid, SubAgg>
+where
+    Grid: GeoGrid,
+    SubAgg: Agg,
+{
+    /// Skips documents outside of the bounding box
+    pub fn bounds(mut self, bounds: GeoBoundingBox) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    /// Skips documents without a value in the `presence_field`.
+    /// The presence field must be a multi-valued fast field.
+    pub fn presence(mut self, presence_field: Field) -> Self {
+        self.presence = Some(presence_field);
+        self
+    }
+}
+
+impl<Grid, SubAgg> Agg for GeoGridAgg<Grid, SubAgg>
+where
+    Grid: GeoGrid,
+    SubAgg: Agg,
+{
+    type Fruit = Terms<Grid::Key, SubAgg::Fruit>;
+    type Child = PreparedGeoGridAgg<Grid, SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        self.grid.validate()?;
+        Ok(Self::Child {
+            lat_field: self.lat_field,
+            lon_field: self.lon_field,
+            presence: self.presence,
+            grid: self.grid,
+            bounds: self.bounds,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedGeoGridAgg<Grid, SubAgg>
+where
+    Grid: GeoGrid,
+    SubAgg: PreparedAgg,
+{
+    lat_field: Field,
+    lon_field: Field,
+    presence: Option<Field>,
+    grid: Grid,
+    bounds: Option<GeoBoundingBox>,
+    sub_agg: SubAgg,
+}
+
+impl<Grid, SubAgg> PreparedAgg for PreparedGeoGridAgg<Grid, SubAgg>
+where
+    Grid: GeoGrid,
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Terms<Grid::Key, SubAgg::Fruit>;
+    type Child = GeoGridSegmentAgg<Grid, SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Terms::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            points: GeoPointReader::open(ctx, self.lat_field, self.lon_field, self.presence)?,
+            grid: self.grid,
+            bounds: self.bounds,
+            key: Grid::Key::default(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        for (key, bucket) in fruit.res {
+            let existing_bucket = harvest.res.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+
+            self.sub_agg.merge(existing_bucket, bucket);
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.res.values_mut().collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+pub struct GeoGridSegmentAgg<Grid, SubAgg>
+where
+    Grid: GeoGrid,
+    SubAgg: SegmentAgg,
+{
+    points: GeoPointReader,
+    grid: Grid,
+    bounds: Option<GeoBoundingBox>,
+    key: Grid::Key,
+    sub_agg: SubAgg,
+}
+
+impl<Grid, SubAgg> SegmentAgg for GeoGridSegmentAgg<Grid, SubAgg>
+where
+    Grid: GeoGrid,
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Terms<Grid::Key, SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Terms::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        let point = match self.points.get(doc) {
+            Some(point) => point,
+            None => return,
+        };
+        if self.bounds.map_or(false, |bounds| !bounds.contains(point)) {
+            return;
+        }
+
+        self.grid.cell(point, &mut self.key);
+        if !fruit.res.contains_key(&self.key) {
+            fruit.res.insert(self.key.clone(), self.sub_agg.create_fruit());
+        }
+        let bucket = fruit.res.get_mut(&self.key).unwrap();
+        self.sub_agg.collect(doc, score, bucket);
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        for bucket in fruit.res.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use crate::geo::{GeoBoundingBox, GeoPoint, GeoTile};
+    use super::{geohash_grid_agg, geotile_grid_agg};
+
+    #[test]
+    fn test_geohash_grid_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = geohash_grid_agg(
+            product_index.schema.lat,
+            product_index.schema.lon,
+            3,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let cells = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            cells.top_k(10, |b| b.0),
+            vec!(
+                (&"u33".to_string(), &(2_u64, Some(9.99_f64))),
+                (&"87z".to_string(), &(1_u64, Some(100.01_f64))),
+                (&"u1x".to_string(), &(1_u64, Some(0.5_f64))),
+                (&"xn7".to_string(), &(1_u64, Some(50.0_f64))),
+            )
+        );
+
+        let agg = geohash_grid_agg(
+            product_index.schema.lat, product_index.schema.lon, 5, count_agg()
+        ).bounds(GeoBoundingBox::new(GeoPoint::new(60.0, -10.0), GeoPoint::new(40.0, 30.0)));
+        let cells = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(cells.top_k(10, |b| *b).len(), 3);
+        assert_eq!(cells.get("u33dc"), Some(&1_u64));
+        assert_eq!(cells.get("xn76c"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geotile_grid_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = geotile_grid_agg(
+            product_index.schema.lat, product_index.schema.lon, 2, count_agg()
+        );
+        let tiles = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            tiles.buckets(),
+            vec!(
+                (&GeoTile::new(2, 0, 1), &1_u64),
+                (&GeoTile::new(2, 2, 1), &3_u64),
+                (&GeoTile::new(2, 3, 1), &1_u64),
+            )
+        );
+
+        // Bounding box crossing the dateline
+        let agg = geotile_grid_agg(
+            product_index.schema.lat, product_index.schema.lon, 6, count_agg()
+        ).bounds(GeoBoundingBox::new(GeoPoint::new(50.0, 130.0), GeoPoint::new(0.0, -150.0)));
+        let tiles = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            tiles.buckets(),
+            vec!(
+                (&GeoTile::new(6, 3, 28), &1_u64),
+                (&GeoTile::new(6, 56, 25), &1_u64),
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geo_grid_agg_presence() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        // Offers have no location, so they are at the null island without the presence field
+        let agg = geotile_grid_agg(
+            product_index.schema.lat, product_index.schema.lon, 1, count_agg()
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (&GeoTile::new(1, 0, 0), &1_u64),
+                (&GeoTile::new(1, 1, 0), &4_u64),
+                (&GeoTile::new(1, 1, 1), &7_u64),
+            )
+        );
+
+        let agg = agg.presence(product_index.schema.location_presence);
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?.buckets(),
+            vec!(
+                (&GeoTile::new(1, 0, 0), &1_u64),
+                (&GeoTile::new(1, 1, 0), &4_u64),
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geo_grid_agg_invalid_precision() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let (lat, lon) = (product_index.schema.lat, product_index.schema.lon);
+        assert!(searcher.agg_search(&AllQuery, &geohash_grid_agg(lat, lon, 0, count_agg())).is_err());
+        assert!(searcher.agg_search(&AllQuery, &geohash_grid_agg(lat, lon, 13, count_agg())).is_err());
+        assert!(searcher.agg_search(&AllQuery, &geohash_grid_agg(lat, lon, 12, count_agg())).is_ok());
+        assert!(searcher.agg_search(&AllQuery, &geotile_grid_agg(lat, lon, 30, count_agg())).is_err());
+        assert!(searcher.agg_search(&AllQuery, &geotile_grid_agg(lat, lon, 29, count_agg())).is_ok());
+
+        Ok(())
+    }
+}
@@ -1,183 +1,638 @@
 use std::collections::BTreeMap;
 
-use tantivy::{DocId, Result, Score, Searcher};
-use tantivy::fastfield::FastFieldReader;
+use tantivy::{DateTime, DocId, Result, Score, Searcher, TantivyError};
+use tantivy::fastfield::{
+    FastFieldNotAvailableError,
+    FastFieldReader,
+    MultiValueIntFastFieldReader,
+};
 use tantivy::schema::Field;
 
 use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::missing::PresenceReader;
 
-pub fn histogram_agg_f64<SubAgg>(
-    field: Field, start: f64, interval: f64, sub_agg: SubAgg
-) -> HistogramAgg<SubAgg>
+/// Values that can be put into histogram buckets
+pub trait HistogramValue: Copy {
+    fn to_f64(self) -> f64;
+}
+
+impl HistogramValue for u64 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl HistogramValue for i64 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl HistogramValue for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+/// Dates are bucketed by the number of seconds since the epoch
+impl HistogramValue for DateTime {
+    fn to_f64(self) -> f64 {
+        self.timestamp() as f64
+    }
+}
+
+macro_rules! impl_histogram_agg_for_type {
+    ( $type:ty, $reader_fn:ident : $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident ) => {
+
+pub fn $agg_fn<SubAgg>(
+    field: Field, interval: f64, sub_agg: SubAgg
+) -> $agg_struct<SubAgg>
 where
     SubAgg: Agg,
 {
-    HistogramAgg {
+    $agg_struct {
         field,
-        start,
-        interval,
+        params: HistogramParams::new(interval),
+        missing: None,
         sub_agg,
     }
 }
 
-pub struct HistogramAgg<SubAgg>
+pub struct $agg_struct<SubAgg>
 where
     SubAgg: Agg,
 {
     field: Field,
-    start: f64,
-    interval: f64,
+    params: HistogramParams,
+    missing: Option<Field>,
     sub_agg: SubAgg,
 }
 
-impl<SubAgg> Agg for HistogramAgg<SubAgg>
+impl<SubAgg> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Shifts bucket boundaries, so buckets start at `offset + n * interval`
+    pub fn offset(mut self, offset: f64) -> Self {
+        self.params.offset = offset;
+        self
+    }
+
+    /// Starts the first bucket at `start` and ignores lower values,
+    /// it also shifts bucket boundaries like the `offset`
+    pub fn start(mut self, start: f64) -> Self {
+        self.params.offset = start;
+        self.params.start = Some(start);
+        self
+    }
+
+    /// Adds empty buckets between `min` and `max` even if there are no values there
+    pub fn extended_bounds(mut self, min: f64, max: f64) -> Self {
+        self.params.extended_bounds = Some((min, max));
+        self
+    }
+
+    /// Ignores values outside of the `min` and `max` inclusive
+    pub fn hard_bounds(mut self, min: f64, max: f64) -> Self {
+        self.params.hard_bounds = Some((min, max));
+        self
+    }
+
+    /// Returns only buckets with at least `min_doc_count` documents.
+    /// By default gaps between buckets are filled with empty buckets.
+    pub fn min_doc_count(mut self, min_doc_count: u64) -> Self {
+        self.params.min_doc_count = min_doc_count;
+        self
+    }
+}
+
+impl<SubAgg> Agg for $agg_struct<SubAgg>
 where
     SubAgg: Agg,
     <SubAgg as Agg>::Child: PreparedAgg,
 {
     type Fruit = Histogram<SubAgg::Fruit>;
-    type Child = PreparedHistogramAgg<SubAgg::Child>;
+    type Child = $prepared_agg_struct<SubAgg::Child>;
 
     fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        self.params.validate()?;
         Ok(Self::Child {
             field: self.field,
-            start: self.start,
-            interval: self.interval,
+            params: self.params,
+            missing: self.missing,
             sub_agg: self.sub_agg.prepare(searcher)?,
         })
     }
 
     fn requires_scoring(&self) -> bool {
-        false
+        self.sub_agg.requires_scoring()
     }
 }
 
-pub struct PreparedHistogramAgg<SubAgg>
+pub struct $prepared_agg_struct<SubAgg>
 where
     SubAgg: PreparedAgg,
 {
     field: Field,
-    start: f64,
-    interval: f64,
+    params: HistogramParams,
+    missing: Option<Field>,
     sub_agg: SubAgg,
 }
 
-impl<SubAgg> PreparedAgg for PreparedHistogramAgg<SubAgg>
+impl<SubAgg> PreparedAgg for $prepared_agg_struct<SubAgg>
 where
     SubAgg: PreparedAgg,
 {
     type Fruit = Histogram<SubAgg::Fruit>;
-    type Child = HistogramSegmentAgg<SubAgg::Child>;
+    type Child = $segment_agg_struct<SubAgg::Child>;
 
     fn create_fruit(&self) -> Self::Fruit {
-        Histogram {
-            start: self.start,
-            interval: self.interval,
-            buckets: BTreeMap::new(),
-        }
+        Histogram::new(self.params)
     }
 
     fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
-        let ff_reader = ctx.reader.fast_fields().f64(self.field).unwrap();
-        Ok(Self::Child::new(
+        let ff_reader = ctx.reader.fast_fields().$reader_fn(self.field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(self.field)
+                )
+            })?;
+        Self::Child::new(
+            ctx,
             ff_reader,
-            self.start,
-            self.interval,
-            self.sub_agg.for_segment(ctx)?,
-        ))
+            self.missing,
+            self.params,
+            self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        )
     }
 
     fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
-        for (key, bucket) in fruit.buckets {
+        for (key, (doc_count, bucket)) in fruit.buckets {
             let existing_bucket = harvest.buckets.entry(key)
-                .or_insert_with(|| self.sub_agg.create_fruit());
+                .or_insert_with(|| (0, self.sub_agg.create_fruit()));
+
+            existing_bucket.0 += doc_count;
+            self.sub_agg.merge(&mut existing_bucket.1, bucket);
+        }
+        if let Some(missing_bucket) = fruit.missing {
+            let existing_bucket = harvest.missing
+                .get_or_insert_with(|| self.sub_agg.create_fruit());
 
-            self.sub_agg.merge(existing_bucket, bucket);
+            self.sub_agg.merge(existing_bucket, missing_bucket);
         }
     }
 
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        if let Some((first, last)) = harvest.filled_range() {
+            if last.saturating_sub(first) >= MAX_BUCKETS {
+                return Err(TantivyError::InvalidArgument(format!(
+                    "Histogram has more than {} buckets, increase the interval", MAX_BUCKETS
+                )));
+            }
+        }
+        self.sub_agg.finalize_buckets(
+            &mut harvest.buckets.values_mut().map(|b| &mut b.1).chain(harvest.missing.as_mut()).collect::<Vec<_>>()
+        )?;
+        Ok(())
+    }
+}
+
+    };
+    ( SINGLE $(|$type:ty, $reader_fn:ident : $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident|),+ ) => { $(
+
+impl_histogram_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
+
+impl<SubAgg> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Collects documents without a value in the `presence_field` into a separate bucket.
+    /// The presence field must be a multi-valued fast field.
+    pub fn missing(mut self, presence_field: Field) -> Self {
+        self.missing = Some(presence_field);
+        self
+    }
 }
 
-pub struct HistogramSegmentAgg<SubAgg>
+pub struct $segment_agg_struct<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
-    ff_reader: FastFieldReader<f64>,
-    start: f64,
-    interval: f64,
+    ff_reader: FastFieldReader<$type>,
+    presence: Option<PresenceReader>,
+    params: HistogramParams,
     sub_agg: SubAgg,
 }
 
-impl<SubAgg> HistogramSegmentAgg<SubAgg>
+impl<SubAgg> $segment_agg_struct<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
-    fn new(ff_reader: FastFieldReader<f64>, start: f64, interval: f64, sub_agg: SubAgg) -> Self {
-        Self {
-            ff_reader, start, interval, sub_agg
-        }
+    fn new(
+        ctx: &AggSegmentContext,
+        ff_reader: FastFieldReader<$type>,
+        missing: Option<Field>,
+        params: HistogramParams,
+        sub_agg: SubAgg,
+    ) -> Result<Self> {
+        let presence = match missing {
+            Some(presence_field) => Some(PresenceReader::open(ctx, presence_field)?),
+            None => None,
+        };
+        Ok(Self {
+            ff_reader, presence, params, sub_agg
+        })
     }
 }
 
-impl<SubAgg> SegmentAgg for HistogramSegmentAgg<SubAgg>
+impl<SubAgg> SegmentAgg for $segment_agg_struct<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
     type Fruit = Histogram<SubAgg::Fruit>;
 
     fn create_fruit(&self) -> Self::Fruit {
-        Histogram {
-            start: self.start,
-            interval: self.interval,
-            buckets: BTreeMap::new(),
-        }
+        Histogram::new(self.params)
     }
 
     fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
-        let k = self.ff_reader.get(doc);
-        if k.is_nan() {
+        if self.presence.as_mut().map_or(false, |presence| presence.is_missing(doc)) {
+            let bucket = fruit.missing
+                .get_or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
             return;
         }
 
-        let n = k - self.start;
-        if n < 0.0 {
+        let value = self.ff_reader.get(doc).to_f64();
+        if let Some(bucket_ord) = self.params.bucket_ord(value) {
+            let bucket = fruit.buckets.entry(bucket_ord)
+                .or_insert_with(|| (0, self.sub_agg.create_fruit()));
+            bucket.0 += 1;
+            self.sub_agg.collect(doc, score, &mut bucket.1);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        for bucket in fruit.buckets.values_mut().map(|b| &mut b.1).chain(fruit.missing.as_mut()) {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+    )* };
+    ( MULTI $(|$type:ty, $reader_fn:ident : $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident|),+ ) => { $(
+
+impl_histogram_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
+
+impl<SubAgg> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Collects documents without values into a separate bucket
+    pub fn missing(mut self) -> Self {
+        self.missing = Some(self.field);
+        self
+    }
+}
+
+pub struct $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    ff_reader: MultiValueIntFastFieldReader<$type>,
+    missing: bool,
+    params: HistogramParams,
+    sub_agg: SubAgg,
+    vals: Vec<$type>,
+    bucket_ords: Vec<i64>,
+}
+
+impl<SubAgg> $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    fn new(
+        _: &AggSegmentContext,
+        ff_reader: MultiValueIntFastFieldReader<$type>,
+        missing: Option<Field>,
+        params: HistogramParams,
+        sub_agg: SubAgg,
+    ) -> Result<Self> {
+        Ok(Self {
+            ff_reader,
+            missing: missing.is_some(),
+            params,
+            sub_agg,
+            vals: vec!(),
+            bucket_ords: vec!(),
+        })
+    }
+}
+
+impl<SubAgg> SegmentAgg for $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Histogram<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Histogram::new(self.params)
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        self.ff_reader.get_vals(doc, &mut self.vals);
+        if self.vals.is_empty() && self.missing {
+            let bucket = fruit.missing
+                .get_or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
             return;
         }
 
-        let bucket_ord = (n / self.interval).floor() as u64;
-        let bucket = fruit.buckets.entry(bucket_ord)
-            .or_insert_with(|| self.sub_agg.create_fruit());
+        // A document is collected once into every bucket its values fall into
+        self.bucket_ords.clear();
+        for &value in self.vals.iter() {
+            if let Some(bucket_ord) = self.params.bucket_ord(value.to_f64()) {
+                self.bucket_ords.push(bucket_ord);
+            }
+        }
+        self.bucket_ords.sort_unstable();
+        self.bucket_ords.dedup();
 
-        self.sub_agg.collect(doc, score, bucket);
+        for &bucket_ord in self.bucket_ords.iter() {
+            let bucket = fruit.buckets.entry(bucket_ord)
+                .or_insert_with(|| (0, self.sub_agg.create_fruit()));
+            bucket.0 += 1;
+            self.sub_agg.collect(doc, score, &mut bucket.1);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        for bucket in fruit.buckets.values_mut().map(|b| &mut b.1).chain(fruit.missing.as_mut()) {
+            self.sub_agg.finish(bucket);
+        }
     }
 }
 
-#[derive(Default, Debug)]
-pub struct Histogram<T> {
-    start: f64,
+    )* };
+}
+
+impl_histogram_agg_for_type!(
+    SINGLE
+    |u64, u64 : histogram_agg_u64, HistogramAggU64, PreparedHistogramAggU64, HistogramSegmentAggU64|,
+    |i64, i64 : histogram_agg_i64, HistogramAggI64, PreparedHistogramAggI64, HistogramSegmentAggI64|,
+    |f64, f64 : histogram_agg_f64, HistogramAggF64, PreparedHistogramAggF64, HistogramSegmentAggF64|,
+    |DateTime, date : histogram_agg_date, HistogramAggDate, PreparedHistogramAggDate, HistogramSegmentAggDate|
+);
+
+impl_histogram_agg_for_type!(
+    MULTI
+    |u64, u64s : histogram_agg_u64s, HistogramAggU64s, PreparedHistogramAggU64s, HistogramSegmentAggU64s|,
+    |i64, i64s : histogram_agg_i64s, HistogramAggI64s, PreparedHistogramAggI64s, HistogramSegmentAggI64s|,
+    |f64, f64s : histogram_agg_f64s, HistogramAggF64s, PreparedHistogramAggF64s, HistogramSegmentAggF64s|,
+    |DateTime, dates : histogram_agg_dates, HistogramAggDates, PreparedHistogramAggDates, HistogramSegmentAggDates|
+);
+
+/// Former names of the f64 histogram aggregation types
+pub type HistogramAgg<SubAgg> = HistogramAggF64<SubAgg>;
+pub type PreparedHistogramAgg<SubAgg> = PreparedHistogramAggF64<SubAgg>;
+pub type HistogramSegmentAgg<SubAgg> = HistogramSegmentAggF64<SubAgg>;
+
+/// Histogram with an interval chosen to split the range of the field values
+/// into approximately `buckets` buckets.
+///
+/// The range is taken from the minimum and maximum values of the field in every segment,
+/// so it covers all the documents and not only matched ones, including deleted documents
+/// and documents without a value that have the default value 0. The interval is rounded
+/// to 1, 2 or 5 multiplied by a power of 10, so the number of returned buckets is approximate.
+pub fn auto_histogram_agg_f64<SubAgg>(
+    field: Field, buckets: usize, sub_agg: SubAgg
+) -> AutoHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+{
+    AutoHistogramAggF64 {
+        field,
+        num_buckets: buckets,
+        missing: None,
+        sub_agg,
+    }
+}
+
+pub struct AutoHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    num_buckets: usize,
+    missing: Option<Field>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> AutoHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Collects documents without a value in the `presence_field` into a separate bucket.
+    /// The presence field must be a multi-valued fast field.
+    pub fn missing(mut self, presence_field: Field) -> Self {
+        self.missing = Some(presence_field);
+        self
+    }
+}
+
+impl<SubAgg> Agg for AutoHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = Histogram<SubAgg::Fruit>;
+    type Child = PreparedHistogramAggF64<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        let mut bounds: Option<(f64, f64)> = None;
+        for segment_reader in searcher.segment_readers() {
+            if segment_reader.num_docs() == 0 {
+                continue;
+            }
+            let ff_reader = segment_reader.fast_fields().f64(self.field)
+                .ok_or_else(|| {
+                    FastFieldNotAvailableError::new(
+                        segment_reader.schema().get_field_entry(self.field)
+                    )
+                })?;
+            let (min, max) = (ff_reader.min_value(), ff_reader.max_value());
+            bounds = Some(match bounds {
+                Some((cur_min, cur_max)) => (cur_min.min(min), cur_max.max(max)),
+                None => (min, max),
+            });
+        }
+        let interval = match bounds {
+            Some((min, max)) => nice_interval((max - min) / self.num_buckets.max(1) as f64),
+            None => 1.0,
+        };
+
+        Ok(Self::Child {
+            field: self.field,
+            params: HistogramParams::new(interval),
+            missing: self.missing,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+/// Rounds the interval up to 1, 2 or 5 multiplied by a power of 10
+fn nice_interval(raw_interval: f64) -> f64 {
+    if !raw_interval.is_finite() || raw_interval <= 0.0 {
+        return 1.0;
+    }
+    let magnitude = 10_f64.powi(raw_interval.log10().floor() as i32);
+    let normalized = raw_interval / magnitude;
+    let nice = if normalized <= 1.0 {
+        1.0
+    } else if normalized <= 2.0 {
+        2.0
+    } else if normalized <= 5.0 {
+        5.0
+    } else {
+        10.0
+    };
+    nice * magnitude
+}
+
+#[derive(Clone, Copy, Debug)]
+struct HistogramParams {
     interval: f64,
-    buckets: BTreeMap<u64, T>,
+    offset: f64,
+    start: Option<f64>,
+    extended_bounds: Option<(f64, f64)>,
+    hard_bounds: Option<(f64, f64)>,
+    min_doc_count: u64,
+}
+
+impl HistogramParams {
+    fn new(interval: f64) -> Self {
+        Self {
+            interval,
+            offset: 0.0,
+            start: None,
+            extended_bounds: None,
+            hard_bounds: None,
+            min_doc_count: 0,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(self.interval.is_finite() && self.interval > 0.0) {
+            return Err(TantivyError::InvalidArgument(
+                format!("Histogram interval must be positive: {}", self.interval)
+            ));
+        }
+        let bounds = self.extended_bounds.iter().chain(self.hard_bounds.iter())
+            .flat_map(|&(min, max)| vec!(min, max));
+        for value in bounds.chain(self.start).chain(Some(self.offset)) {
+            if !value.is_finite() {
+                return Err(TantivyError::InvalidArgument(
+                    format!("Histogram bounds and offset must be finite: {}", value)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn bucket_ord(&self, value: f64) -> Option<i64> {
+        if value.is_nan() || self.start.map_or(false, |start| value < start) {
+            return None;
+        }
+        if let Some((min, max)) = self.hard_bounds {
+            if value < min || value > max {
+                return None;
+            }
+        }
+        Some(((value - self.offset) / self.interval).floor() as i64)
+    }
+
+    fn bucket_key(&self, bucket_ord: i64) -> f64 {
+        bucket_ord as f64 * self.interval + self.offset
+    }
+}
+
+/// Histograms with more buckets between the first and the last one are rejected,
+/// so a small interval or wide extended bounds cannot exhaust the memory
+const MAX_BUCKETS: i64 = 65_536;
+
+#[derive(Debug)]
+pub struct Histogram<T> {
+    params: HistogramParams,
+    buckets: BTreeMap<i64, (u64, T)>,
+    missing: Option<T>,
 }
 
 impl<T> Histogram<T> {
+    fn new(params: HistogramParams) -> Self {
+        Self {
+            params,
+            buckets: BTreeMap::new(),
+            missing: None,
+        }
+    }
+
+    pub fn missing(&self) -> Option<&T> {
+        self.missing.as_ref()
+    }
+
+    /// Buckets ordered by key. Gaps between buckets and extended bounds
+    /// are filled with empty buckets unless `min_doc_count` is set.
     pub fn buckets(&self) -> Vec<(f64, Option<&T>)> {
-        let mut res = vec!();
-        let mut last_bucket_ord = if let Some(&bucket_ord) = self.buckets.keys().next() {
-            bucket_ord
-        } else {
-            return res;
-        };
-        for (bucket_ord, agg) in self.buckets.iter() {
-            let gap = bucket_ord - last_bucket_ord;
-            if gap > 1 {
-                for i in 0_u64..(gap - 1) {
-                    res.push(((last_bucket_ord + i + 1) as f64 * self.interval + self.start, None));
-                }
+        let params = &self.params;
+        if params.min_doc_count > 0 {
+            return self.buckets.iter()
+                .filter(|(_, (doc_count, _))| *doc_count >= params.min_doc_count)
+                .map(|(&bucket_ord, (_, agg))| (params.bucket_key(bucket_ord), Some(agg)))
+                .collect();
+        }
+
+        match self.filled_range() {
+            Some((first, last)) => (first..=last)
+                .map(|bucket_ord| {
+                    (params.bucket_key(bucket_ord), self.buckets.get(&bucket_ord).map(|b| &b.1))
+                })
+                .collect(),
+            None => vec!(),
+        }
+    }
+
+    /// First and last bucket ordinals when the gaps are filled with empty buckets
+    fn filled_range(&self) -> Option<(i64, i64)> {
+        let params = &self.params;
+        if params.min_doc_count > 0 {
+            return None;
+        }
+        let mut first_and_last = self.buckets.keys().next()
+            .zip(self.buckets.keys().next_back())
+            .map(|(&first, &last)| (first, last));
+        if let Some((mut min, mut max)) = params.extended_bounds {
+            if let Some((hard_min, hard_max)) = params.hard_bounds {
+                min = min.max(hard_min);
+                max = max.min(hard_max);
+            }
+            if let Some(start) = params.start {
+                min = min.max(start);
+            }
+            if min <= max {
+                let (min_ord, max_ord) = (
+                    params.bucket_ord(min).unwrap_or(i64::MAX),
+                    params.bucket_ord(max).unwrap_or(i64::MIN),
+                );
+                first_and_last = match first_and_last {
+                    Some((first, last)) => Some((first.min(min_ord), last.max(max_ord))),
+                    None => Some((min_ord, max_ord)),
+                };
             }
-            res.push((*bucket_ord as f64 * self.interval + self.start, Some(&agg)));
-            last_bucket_ord = *bucket_ord;
         }
-        res
+        first_and_last
     }
 }
 
@@ -189,7 +644,10 @@ mod tests {
     use test_fixtures::ProductIndex;
 
     use crate::{AggSearcher, count_agg, filter_agg, terms_agg_u64s};
-    use super::histogram_agg_f64;
+    use super::{
+        auto_histogram_agg_f64, histogram_agg_f64, histogram_agg_u64, histogram_agg_u64s,
+        nice_interval,
+    };
 
     #[test]
     fn test_histogram_agg() -> Result<()> {
@@ -199,7 +657,7 @@ mod tests {
         let searcher = product_index.reader.searcher();
 
         let price_hist_agg = histogram_agg_f64(
-            product_index.schema.price, 0.0, 10.0, count_agg()
+            product_index.schema.price, 10.0, count_agg()
         );
         let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
         assert_eq!(
@@ -230,8 +688,55 @@ mod tests {
         let searcher = product_index.reader.searcher();
 
         let price_hist_agg = histogram_agg_f64(
-            product_index.schema.price, 35.0, 10.0, count_agg()
+            product_index.schema.price, 10.0, count_agg()
+        ).start(35.0);
+        let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
+        assert_eq!(
+            price_hist.buckets(),
+            vec!(
+                (45.0_f64, Some(&1_u64)),
+                (55.0_f64, None),
+                (65.0_f64, None),
+                (75.0_f64, None),
+                (85.0_f64, None),
+                (95.0_f64, Some(&1_u64)),
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_histogram_agg_with_offset() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3_u16)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).offset(35.0);
+        let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
+        assert_eq!(
+            price_hist.buckets(),
+            vec!(
+                (-5.0_f64, Some(&1_u64)),
+                (5.0_f64, Some(&2_u64)),
+                (15.0_f64, None),
+                (25.0_f64, None),
+                (35.0_f64, None),
+                (45.0_f64, Some(&1_u64)),
+                (55.0_f64, None),
+                (65.0_f64, None),
+                (75.0_f64, None),
+                (85.0_f64, None),
+                (95.0_f64, Some(&1_u64)),
+            )
         );
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).offset(35.0).hard_bounds(35.0, f64::MAX);
         let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
         assert_eq!(
             price_hist.buckets(),
@@ -248,6 +753,216 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_histogram_agg_bounds() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3_u16)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).hard_bounds(5.0, 30.0).extended_bounds(-20.0, 40.0);
+        let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
+        assert_eq!(
+            price_hist.buckets(),
+            vec!(
+                (0.0_f64, Some(&1_u64)),
+                (10.0_f64, Some(&1_u64)),
+                (20.0_f64, None),
+                (30.0_f64, None),
+            )
+        );
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).extended_bounds(-20.0, 40.0);
+        let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
+        let buckets = price_hist.buckets();
+        assert_eq!(buckets.len(), 13);
+        assert_eq!(buckets[0], (-20.0_f64, None));
+        assert_eq!(buckets[12], (100.0_f64, Some(&1_u64)));
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).min_doc_count(1).extended_bounds(-20.0, 40.0);
+        let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
+        assert_eq!(
+            price_hist.buckets(),
+            vec!(
+                (0.0_f64, Some(&2_u64)),
+                (10.0_f64, Some(&1_u64)),
+                (50.0_f64, Some(&1_u64)),
+                (100.0_f64, Some(&1_u64)),
+            )
+        );
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).min_doc_count(2);
+        let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
+        assert_eq!(price_hist.buckets(), vec!((0.0_f64, Some(&2_u64))));
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).extended_bounds(0.0, f64::INFINITY);
+        assert!(searcher.agg_search(&AllQuery, &price_hist_agg).is_err());
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).extended_bounds(0.0, 1e12);
+        assert!(searcher.agg_search(&AllQuery, &price_hist_agg).is_err());
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 1e-6, count_agg()
+        );
+        assert!(searcher.agg_search(&AllQuery, &price_hist_agg).is_err());
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 1e-6, count_agg()
+        ).min_doc_count(1);
+        assert_eq!(searcher.agg_search(&AllQuery, &price_hist_agg)?.buckets().len(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_histogram_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3_u16)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let price_hist = searcher.agg_search(
+            &AllQuery,
+            &auto_histogram_agg_f64(product_index.schema.price, 4, count_agg())
+        )?;
+        assert_eq!(
+            price_hist.buckets(),
+            vec!(
+                (0.0_f64, Some(&3_u64)),
+                (50.0_f64, Some(&1_u64)),
+                (100.0_f64, Some(&1_u64)),
+            )
+        );
+
+        let price_hist = searcher.agg_search(
+            &AllQuery,
+            &auto_histogram_agg_f64(product_index.schema.price, 10, count_agg())
+        )?;
+        assert_eq!(price_hist.buckets().len(), 11);
+        assert_eq!(price_hist.buckets()[1], (10.0_f64, Some(&1_u64)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_histogram_agg_missing() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3_u16)?;
+        product_index.index_test_products_with_offers()?;
+
+        let searcher = product_index.reader.searcher();
+
+        // Offers have neither tags nor location, their default latitude 0 is still in the range
+        let lat_hist = searcher.agg_search(
+            &AllQuery,
+            &auto_histogram_agg_f64(product_index.schema.lat, 4, count_agg())
+                .missing(product_index.schema.tag_ids)
+        )?;
+        assert_eq!(
+            lat_hist.buckets(),
+            vec!(
+                (20.0_f64, Some(&2_u64)),
+                (40.0_f64, Some(&3_u64)),
+            )
+        );
+        assert_eq!(lat_hist.missing(), Some(&7_u64));
+
+        let lat_hist = searcher.agg_search(
+            &AllQuery,
+            &auto_histogram_agg_f64(product_index.schema.lat, 4, count_agg())
+        )?;
+        assert_eq!(
+            lat_hist.buckets(),
+            vec!(
+                (0.0_f64, Some(&7_u64)),
+                (20.0_f64, Some(&2_u64)),
+                (40.0_f64, Some(&3_u64)),
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nice_interval() {
+        assert_eq!(nice_interval(9.951), 10.0);
+        assert_eq!(nice_interval(24.88), 50.0);
+        assert_eq!(nice_interval(1.5), 2.0);
+        assert_eq!(nice_interval(0.0), 1.0);
+    }
+
+    #[test]
+    fn test_histogram_agg_missing() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3_u16)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let price_hist_agg = histogram_agg_f64(
+            product_index.schema.price, 10.0, count_agg()
+        ).missing(product_index.schema.attr_facets);
+        let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
+        assert_eq!(
+            price_hist.buckets(),
+            vec!(
+                (0.0_f64, Some(&1_u64)),
+                (10.0_f64, Some(&1_u64)),
+            )
+        );
+        assert_eq!(price_hist.missing(), Some(&3_u64));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_histogram_agg_int_fields() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3_u16)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let opinion_hist_agg = histogram_agg_u64(
+            product_index.schema.positive_opinion_percent, 10.0, count_agg()
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &opinion_hist_agg)?.buckets(),
+            vec!(
+                (70.0_f64, Some(&1_u64)),
+                (80.0_f64, Some(&2_u64)),
+                (90.0_f64, Some(&1_u64)),
+                (100.0_f64, Some(&1_u64)),
+            )
+        );
+
+        // Every document is counted once per bucket
+        let tags_hist_agg = histogram_agg_u64s(
+            product_index.schema.tag_ids, 100.0, count_agg()
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &tags_hist_agg)?.buckets(),
+            vec!(
+                (100.0_f64, Some(&2_u64)),
+                (200.0_f64, Some(&3_u64)),
+                (300.0_f64, Some(&4_u64)),
+                (400.0_f64, None),
+                (500.0_f64, Some(&1_u64)),
+            )
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_nested_histogram_agg() -> Result<()> {
         let mut product_index = ProductIndex::create_in_ram(3_u16)?;
@@ -259,7 +974,7 @@ mod tests {
             (
                 count_agg(),
                 histogram_agg_f64(
-                    product_index.schema.price, 0.0, 10.0, count_agg()
+                    product_index.schema.price, 10.0, count_agg()
                 )
             )
         );
@@ -347,7 +1062,7 @@ mod tests {
         let price_hist_agg = filter_agg(
             &price_query,
             histogram_agg_f64(
-                product_index.schema.price, 0.0, 10.0, count_agg()
+                product_index.schema.price, 10.0, count_agg()
             )
         );
         let price_hist = searcher.agg_search(&AllQuery, &price_hist_agg)?;
@@ -1,10 +1,58 @@
+pub mod adjacency_matrix;
+pub mod attribute_facets;
+pub mod children;
+pub mod composite;
+pub mod facet;
+pub mod geo_distance;
+pub mod geo_grid;
 pub mod histogram;
+pub mod multi_terms;
+pub mod nested;
+pub mod rare_terms;
+pub mod sampler;
+pub mod significant_terms;
 pub mod terms;
+pub mod terms_by;
+pub mod terms_str;
+pub mod variable_width_histogram;
 
-pub use histogram::histogram_agg_f64;
+pub use adjacency_matrix::adjacency_matrix_agg;
+pub use attribute_facets::attribute_facets_agg;
+pub use children::children_agg;
+pub use composite::{composite_agg, date_histogram_source, histogram_source_f64};
+pub use facet::facet_agg;
+pub use geo_distance::geo_distance_agg;
+pub use geo_grid::{geohash_grid_agg, geotile_grid_agg};
+pub use histogram::{
+    auto_histogram_agg_f64,
+    histogram_agg_date, histogram_agg_dates,
+    histogram_agg_f64, histogram_agg_f64s,
+    histogram_agg_i64, histogram_agg_i64s,
+    histogram_agg_u64, histogram_agg_u64s,
+};
+pub use multi_terms::{
+    multi_terms_agg,
+    terms_source_i64, terms_source_i64s,
+    terms_source_u64, terms_source_u64s,
+};
+pub use nested::{nested_agg, reverse_nested_agg};
+pub use rare_terms::{
+    rare_terms_agg_i64, rare_terms_agg_i64s,
+    rare_terms_agg_u64, rare_terms_agg_u64s,
+};
+pub use sampler::{diversified_sampler_agg, sampler_agg};
+pub use significant_terms::{
+    SignificanceHeuristic,
+    significant_terms_agg_i64, significant_terms_agg_i64s,
+    significant_terms_agg_u64, significant_terms_agg_u64s,
+};
 pub use terms::{
+    Direction, TermsOrder, TermsSet,
     filtered_terms_agg_i64, filtered_terms_agg_i64s,
     filtered_terms_agg_u64, filtered_terms_agg_u64s,
     terms_agg_i64, terms_agg_i64s,
     terms_agg_u64, terms_agg_u64s,
 };
+pub use terms_by::terms_by_agg;
+pub use terms_str::terms_agg_str;
+pub use variable_width_histogram::variable_width_histogram_agg_f64;
@@ -0,0 +1,419 @@
+use std::hash::Hash;
+
+use tantivy::{DocId, Result, Score, Searcher};
+use tantivy::fastfield::{
+    FastFieldNotAvailableError,
+    FastFieldReader,
+    MultiValueIntFastFieldReader,
+};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use super::terms::{Terms, TermsOptions, TermsOrder};
+
+/// Provides bucket keys of documents
+pub trait KeySource: Sync {
+    type Key: Copy + Eq + Hash + Ord + Send + Sync;
+    type Reader: KeyReader<Key = Self::Key>;
+
+    fn reader(&self, ctx: &AggSegmentContext) -> Result<Self::Reader>;
+
+    /// Checks options of the source before searching
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub trait KeyReader {
+    type Key;
+
+    /// Replaces `keys` with the keys of the document
+    fn read(&mut self, doc: DocId, keys: &mut Vec<Self::Key>);
+}
+
+macro_rules! impl_key_source_for_type {
+    ( SINGLE $(|$type:ty, $reader_fn:ident : $source_fn:ident, $source_struct:ident|),+ ) => { $(
+
+#[derive(Clone, Copy)]
+pub struct $source_struct {
+    field: Field,
+}
+
+pub fn $source_fn(field: Field) -> $source_struct {
+    $source_struct { field }
+}
+
+impl KeySource for $source_struct {
+    type Key = $type;
+    type Reader = FastFieldReader<$type>;
+
+    fn reader(&self, ctx: &AggSegmentContext) -> Result<Self::Reader> {
+        Ok(
+            ctx.reader.fast_fields().$reader_fn(self.field)
+                .ok_or_else(|| {
+                    FastFieldNotAvailableError::new(
+                        ctx.reader.schema().get_field_entry(self.field)
+                    )
+                })?
+        )
+    }
+}
+
+impl KeyReader for FastFieldReader<$type> {
+    type Key = $type;
+
+    fn read(&mut self, doc: DocId, keys: &mut Vec<Self::Key>) {
+        keys.clear();
+        keys.push(self.get(doc));
+    }
+}
+
+    )* };
+    ( MULTI $(|$type:ty, $reader_fn:ident : $source_fn:ident, $source_struct:ident|),+ ) => { $(
+
+#[derive(Clone, Copy)]
+pub struct $source_struct {
+    field: Field,
+}
+
+pub fn $source_fn(field: Field) -> $source_struct {
+    $source_struct { field }
+}
+
+impl KeySource for $source_struct {
+    type Key = $type;
+    type Reader = MultiValueIntFastFieldReader<$type>;
+
+    fn reader(&self, ctx: &AggSegmentContext) -> Result<Self::Reader> {
+        Ok(
+            ctx.reader.fast_fields().$reader_fn(self.field)
+                .ok_or_else(|| {
+                    FastFieldNotAvailableError::new(
+                        ctx.reader.schema().get_field_entry(self.field)
+                    )
+                })?
+        )
+    }
+}
+
+impl KeyReader for MultiValueIntFastFieldReader<$type> {
+    type Key = $type;
+
+    fn read(&mut self, doc: DocId, keys: &mut Vec<Self::Key>) {
+        self.get_vals(doc, keys);
+    }
+}
+
+    )* };
+}
+
+impl_key_source_for_type!(
+    SINGLE
+    |u64, u64 : terms_source_u64, TermsSourceU64|,
+    |i64, i64 : terms_source_i64, TermsSourceI64|
+);
+
+impl_key_source_for_type!(
+    MULTI
+    |u64, u64s : terms_source_u64s, TermsSourceU64s|,
+    |i64, i64s : terms_source_i64s, TermsSourceI64s|
+);
+
+/// Readers of the tuple source with the buffers for their keys
+pub struct TupleKeyReader<R, K> {
+    readers: R,
+    keys: K,
+}
+
+macro_rules! impl_key_source_for_tuple {
+    ( $( $s:ident => $n:tt ),+ ) => {
+
+impl<$($s,)*> KeySource for ($($s,)*)
+where $(
+    $s: KeySource,
+)*
+{
+    type Key = ($($s::Key,)*);
+    type Reader = TupleKeyReader<($($s::Reader,)*), ($(Vec<$s::Key>,)*)>;
+
+    fn reader(&self, ctx: &AggSegmentContext) -> Result<Self::Reader> {
+        Ok(TupleKeyReader {
+            readers: ($(self.$n.reader(ctx)?,)*),
+            keys: ($(Vec::<$s::Key>::new(),)*),
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        $(
+            self.$n.validate()?;
+        )*
+        Ok(())
+    }
+}
+
+impl<$($s,)*> KeyReader for TupleKeyReader<($($s,)*), ($(Vec<$s::Key>,)*)>
+where $(
+    $s: KeyReader,
+    $s::Key: Copy,
+)*
+{
+    type Key = ($($s::Key,)*);
+
+    fn read(&mut self, doc: DocId, keys: &mut Vec<Self::Key>) {
+        keys.clear();
+        $(
+            self.readers.$n.read(doc, &mut self.keys.$n);
+        )*
+        // Cartesian product of the keys of every source
+        let lens = [$(self.keys.$n.len(),)*];
+        let mut strides = lens;
+        let mut num_keys = 1;
+        for (stride, len) in strides.iter_mut().zip(lens.iter()) {
+            *stride = num_keys;
+            num_keys *= len;
+        }
+        for key_ix in 0..num_keys {
+            keys.push(($(
+                self.keys.$n[key_ix / strides[$n] % lens[$n]],
+            )*));
+        }
+    }
+}
+
+    };
+}
+
+impl_key_source_for_tuple!(A => 0, B => 1);
+impl_key_source_for_tuple!(A => 0, B => 1, C => 2);
+impl_key_source_for_tuple!(A => 0, B => 1, C => 2, D => 3);
+
+/// Buckets documents by combinations of keys from several sources.
+///
+/// Documents with multiple values get into a bucket for every combination,
+/// documents without a value in any of the sources are skipped.
+pub fn multi_terms_agg<Sources, SubAgg>(
+    sources: Sources, sub_agg: SubAgg
+) -> MultiTermsAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    MultiTermsAgg {
+        sources,
+        options: TermsOptions::default(),
+        sub_agg,
+    }
+}
+
+pub struct MultiTermsAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    sources: Sources,
+    options: TermsOptions<SubAgg::Fruit>,
+    sub_agg: SubAgg,
+}
+
+impl<Sources, SubAgg> MultiTermsAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    /// Keeps only `size` first buckets according to the order,
+    /// by default all the buckets are returned
+    pub fn size(mut self, size: usize) -> Self {
+        self.options.size = Some(size);
+        self
+    }
+
+    /// Number of buckets kept for every segment, defaults to `size * 1.5 + 10`.
+    /// Less buckets means less memory but bigger error of the document counts.
+    pub fn shard_size(mut self, shard_size: usize) -> Self {
+        self.options.shard_size = Some(shard_size);
+        self
+    }
+
+    /// Order of the buckets, defaults to the document count descending
+    /// when the buckets are pruned and to the key ascending otherwise
+    pub fn order(mut self, order: TermsOrder<SubAgg::Fruit>) -> Self {
+        self.options.order = Some(order);
+        self
+    }
+
+    /// Removes buckets with less documents
+    pub fn min_doc_count(mut self, min_doc_count: u64) -> Self {
+        self.options.min_doc_count = min_doc_count;
+        self
+    }
+}
+
+impl<Sources, SubAgg> Agg for MultiTermsAgg<Sources, SubAgg>
+where
+    Sources: KeySource + Clone,
+    SubAgg: Agg,
+{
+    type Fruit = Terms<Sources::Key, SubAgg::Fruit>;
+    type Child = PreparedMultiTermsAgg<Sources, SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        self.sources.validate()?;
+        Ok(Self::Child {
+            sources: self.sources.clone(),
+            options: self.options.prepare(),
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedMultiTermsAgg<Sources, SubAgg>
+where
+    Sources: KeySource,
+    SubAgg: PreparedAgg,
+{
+    sources: Sources,
+    options: TermsOptions<SubAgg::Fruit>,
+    sub_agg: SubAgg,
+}
+
+impl<Sources, SubAgg> PreparedAgg for PreparedMultiTermsAgg<Sources, SubAgg>
+where
+    Sources: KeySource,
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Terms<Sources::Key, SubAgg::Fruit>;
+    type Child = MultiTermsSegmentAgg<Sources::Reader, SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Terms::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            reader: self.sources.reader(ctx)?,
+            keys: vec!(),
+            counts_docs: self.options.counts_docs(),
+            options: self.options.clone(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        harvest.merge(fruit, &self.sub_agg);
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.res.values_mut().collect::<Vec<_>>())?;
+        self.options.finalize(harvest);
+        Ok(())
+    }
+}
+
+pub struct MultiTermsSegmentAgg<Reader, SubAgg>
+where
+    Reader: KeyReader,
+    SubAgg: SegmentAgg,
+{
+    reader: Reader,
+    keys: Vec<Reader::Key>,
+    options: TermsOptions<SubAgg::Fruit>,
+    counts_docs: bool,
+    sub_agg: SubAgg,
+}
+
+impl<Reader, SubAgg> SegmentAgg for MultiTermsSegmentAgg<Reader, SubAgg>
+where
+    Reader: KeyReader,
+    Reader::Key: Copy + Eq + Hash + Ord,
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Terms<Reader::Key, SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Terms::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
+        self.reader.read(doc, &mut self.keys);
+        for &key in self.keys.iter() {
+            if self.counts_docs {
+                *agg_value.doc_counts.entry(key).or_insert(0) += 1;
+            }
+            let bucket = agg_value.res.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
+        }
+    }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for bucket in agg_value.res.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+        self.options.finish_segment(agg_value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use super::{multi_terms_agg, terms_source_u64, terms_source_u64s};
+
+    #[test]
+    fn test_multi_terms_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = multi_terms_agg(
+            (
+                terms_source_u64(product_index.schema.category_id),
+                terms_source_u64s(product_index.schema.tag_ids),
+            ),
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let cat_tags = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(cat_tags.get(&(1_u64, 111_u64)), Some(&(2_u64, Some(9.99_f64))));
+        assert_eq!(cat_tags.get(&(1_u64, 320_u64)), Some(&(1_u64, Some(10.0_f64))));
+        assert_eq!(cat_tags.get(&(2_u64, 211_u64)), Some(&(1_u64, Some(0.5_f64))));
+        assert_eq!(cat_tags.get(&(2_u64, 112_u64)), None);
+        assert_eq!(cat_tags.top_k(10, |b| b.0).len(), 8);
+
+        let cat_tags = searcher.agg_search(&product_index.category_query(2), &agg)?;
+        assert_eq!(
+            cat_tags.top_k(1, |b| b.0),
+            vec!(
+                (&(2_u64, 311_u64), &(2_u64, Some(0.5_f64))),
+            )
+        );
+
+        let agg = multi_terms_agg(
+            (
+                terms_source_u64(product_index.schema.category_id),
+                terms_source_u64s(product_index.schema.tag_ids),
+            ),
+            count_agg()
+        )
+            .size(2);
+        let cat_tags = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            cat_tags.buckets(),
+            vec!(
+                (&(1_u64, 111_u64), &2_u64),
+                (&(1_u64, 211_u64), &2_u64),
+            )
+        );
+        assert_eq!(cat_tags.doc_count(&(1_u64, 111_u64)), Some(2_u64));
+        assert_eq!(cat_tags.sum_other_doc_count(), 7);
+
+        Ok(())
+    }
+}
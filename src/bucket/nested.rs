@@ -0,0 +1,329 @@
+use std::sync::Arc;
+
+use tantivy::{DocId, Result, Score, Searcher, SegmentReader, TantivyError};
+use tantivy::query::{Query, Weight};
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::parent_docs::ParentDocs;
+
+/// Aggregates child documents of the matched parent documents.
+///
+/// Parents are the documents matching the `parent_filter` query,
+/// every parent must be followed by its children in the segment.
+pub fn nested_agg<'q, SubAgg>(parent_filter: &'q dyn Query, sub_agg: SubAgg) -> NestedAgg<'q, SubAgg>
+where
+    SubAgg: Agg,
+{
+    NestedAgg {
+        parent_filter,
+        sub_agg,
+    }
+}
+
+pub struct NestedAgg<'q, SubAgg>
+where
+    SubAgg: Agg,
+{
+    parent_filter: &'q dyn Query,
+    sub_agg: SubAgg,
+}
+
+impl<'q, SubAgg> Agg for NestedAgg<'q, SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = SubAgg::Fruit;
+    type Child = PreparedNestedAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            weight: self.parent_filter.weight(searcher, false)?,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedNestedAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    weight: Box<dyn Weight>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedNestedAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = SubAgg::Fruit;
+    type Child = NestedSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        self.sub_agg.create_fruit()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        // Deleted parents are kept as they still bound the children of the previous parent
+        let mut parent_docs = ParentDocs::new(ctx.reader.max_doc());
+        let mut scorer = self.weight.scorer(ctx.reader)?;
+        scorer.for_each(&mut |doc, _| parent_docs.insert(doc));
+        let parent_docs = Arc::new(parent_docs);
+
+        let nested_ctx = ctx.with_parent_docs(Some(parent_docs.clone()));
+        Ok(Self::Child {
+            reader: ctx.reader.clone(),
+            parent_docs,
+            sub_agg: self.sub_agg.for_segment(&nested_ctx)?,
+        })
+    }
+
+    fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
+        self.sub_agg.merge(acc, fruit);
+    }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize(fruit)
+    }
+}
+
+pub struct NestedSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    reader: SegmentReader,
+    parent_docs: Arc<ParentDocs>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for NestedSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = SubAgg::Fruit;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        self.sub_agg.create_fruit()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        if !self.parent_docs.contains(doc) {
+            return;
+        }
+        for child_doc in self.parent_docs.children(doc) {
+            if !self.reader.is_deleted(child_doc) {
+                self.sub_agg.collect(child_doc, score, fruit);
+            }
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        self.sub_agg.finish(fruit);
+    }
+}
+
+/// Aggregates parent documents of the child documents inside of a nested aggregation.
+///
+/// Every parent is aggregated once even if several of its children are collected.
+pub fn reverse_nested_agg<SubAgg>(sub_agg: SubAgg) -> ReverseNestedAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    ReverseNestedAgg { sub_agg }
+}
+
+pub struct ReverseNestedAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> Agg for ReverseNestedAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = ReverseNested<SubAgg::Fruit>;
+    type Child = PreparedReverseNestedAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedReverseNestedAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedReverseNestedAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = ReverseNested<SubAgg::Fruit>;
+    type Child = ReverseNestedSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        ReverseNested::new(self.sub_agg.create_fruit())
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let parent_docs = ctx.parent_docs().cloned()
+            .ok_or_else(|| {
+                TantivyError::InvalidArgument(
+                    "Reverse nested aggregation must be inside of a nested aggregation".to_string()
+                )
+            })?;
+        let parent_ctx = ctx.with_parent_docs(None);
+        Ok(Self::Child {
+            parent_docs,
+            sub_agg: self.sub_agg.for_segment(&parent_ctx)?,
+        })
+    }
+
+    fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
+        self.sub_agg.merge(&mut acc.sub_agg, fruit.sub_agg);
+    }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize(&mut fruit.sub_agg)
+    }
+}
+
+pub struct ReverseNestedSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    parent_docs: Arc<ParentDocs>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for ReverseNestedSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = ReverseNested<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        ReverseNested::new(self.sub_agg.create_fruit())
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        // Children come in the increasing order so children of a parent are collected in a row
+        if let Some(parent_doc) = self.parent_docs.prev_parent(doc) {
+            if fruit.last_parent_doc != Some(parent_doc) {
+                fruit.last_parent_doc = Some(parent_doc);
+                self.sub_agg.collect(parent_doc, score, &mut fruit.sub_agg);
+            }
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        fruit.last_parent_doc = None;
+        self.sub_agg.finish(&mut fruit.sub_agg);
+    }
+}
+
+#[derive(Debug)]
+pub struct ReverseNested<T> {
+    last_parent_doc: Option<DocId>,
+    sub_agg: T,
+}
+
+impl<T> ReverseNested<T> {
+    fn new(sub_agg: T) -> Self {
+        Self {
+            last_parent_doc: None,
+            sub_agg,
+        }
+    }
+
+    pub fn sub_agg(&self) -> &T {
+        &self.sub_agg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::{AllQuery, RangeQuery};
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, filter_agg, min_agg_f64, terms_agg_u64};
+    use super::{nested_agg, reverse_nested_agg};
+
+    #[test]
+    fn test_nested_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        let products_query = RangeQuery::new_u64(product_index.schema.id, 1..100);
+        let agg = nested_agg(
+            &products_query,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let offers = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(offers, (7_u64, Some(9.49_f64)));
+
+        let offers = searcher.agg_search(&product_index.category_query(2), &agg)?;
+        assert_eq!(offers, (3_u64, Some(45_f64)));
+
+        let cheap_products_query = RangeQuery::new_f64(product_index.schema.price, 0_f64..10_f64);
+        let offers = searcher.agg_search(
+            &products_query,
+            &filter_agg(&cheap_products_query, agg)
+        )?;
+        assert_eq!(offers, (3_u64, Some(9.49_f64)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_nested_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        let products_query = RangeQuery::new_u64(product_index.schema.id, 1..100);
+        let agg = nested_agg(
+            &products_query,
+            terms_agg_u64(
+                product_index.schema.shop_id,
+                (
+                    count_agg(),
+                    reverse_nested_agg((count_agg(), min_agg_f64(product_index.schema.price)))
+                )
+            )
+        );
+        let shops = searcher.agg_search(&AllQuery, &agg)?;
+        let shops = shops.buckets().into_iter()
+            .map(|(shop_id, (num_offers, products))| (*shop_id, *num_offers, *products.sub_agg()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            shops,
+            vec!(
+                (1_u64, 3_u64, (2_u64, Some(9.99_f64))),
+                (2_u64, 2_u64, (2_u64, Some(9.99_f64))),
+                (3_u64, 2_u64, (2_u64, Some(50_f64))),
+            )
+        );
+
+        let res = searcher.agg_search(&AllQuery, &reverse_nested_agg(count_agg()));
+        assert!(res.is_err());
+
+        Ok(())
+    }
+}
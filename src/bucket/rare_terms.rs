@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::hash::{Hash, Hasher};
+
+use tantivy::{DocId, Result, Score, Searcher, TantivyError};
+use tantivy::fastfield::{
+    FastFieldNotAvailableError,
+    FastFieldReader,
+    MultiValueIntFastFieldReader,
+};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+
+const DEFAULT_MAX_DOC_COUNT: u64 = 1;
+const DEFAULT_PRECISION: f64 = 0.001;
+const BLOOM_FILTER_CAPACITY: usize = 10_000;
+
+macro_rules! impl_rare_terms_agg_for_type {
+    ( $type:ty, $reader_fn:ident : $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident ) => {
+
+/// Collects terms that occur in at most `max_doc_count` documents.
+///
+/// Terms that exceed the limit are moved out from the buckets into a bloom filter,
+/// so memory is not wasted on frequent terms. Rarely a rare term can be lost
+/// due to a false positive of the filter, its probability is controlled by `precision`.
+pub struct $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    max_doc_count: u64,
+    precision: f64,
+    sub_agg: SubAgg,
+}
+
+pub fn $agg_fn<SubAgg>(field: Field, sub_agg: SubAgg) -> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    $agg_struct {
+        field,
+        max_doc_count: DEFAULT_MAX_DOC_COUNT,
+        precision: DEFAULT_PRECISION,
+        sub_agg,
+    }
+}
+
+impl<SubAgg> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Maximum number of documents a term can occur in, defaults to 1
+    pub fn max_doc_count(mut self, max_doc_count: u64) -> Self {
+        self.max_doc_count = max_doc_count;
+        self
+    }
+
+    /// False positive probability of the frequent terms filter between 0 and 1 exclusive,
+    /// defaults to 0.001
+    pub fn precision(mut self, precision: f64) -> Self {
+        self.precision = precision;
+        self
+    }
+}
+
+impl<SubAgg> Agg for $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = RareTerms<$type, SubAgg::Fruit>;
+    type Child = $prepared_agg_struct<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        if !(self.precision > 0.0 && self.precision < 1.0) {
+            return Err(TantivyError::InvalidArgument(
+                format!("Precision must be between 0 and 1: {}", self.precision)
+            ));
+        }
+        Ok(Self::Child {
+            field: self.field,
+            max_doc_count: self.max_doc_count,
+            precision: self.precision,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct $prepared_agg_struct<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    field: Field,
+    max_doc_count: u64,
+    precision: f64,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for $prepared_agg_struct<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = RareTerms<$type, SubAgg::Fruit>;
+    type Child = $segment_agg_struct<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        RareTerms::new(self.max_doc_count, self.precision)
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let ff_reader = ctx.reader.fast_fields().$reader_fn(self.field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(self.field)
+                )
+            })?;
+        Ok(Self::Child::new(
+            ff_reader, self.max_doc_count, self.precision, self.sub_agg.for_segment(&ctx.sub_agg_ctx())?
+        ))
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        harvest.frequent.merge(fruit.frequent);
+        let frequent = &harvest.frequent;
+        harvest.res.retain(|key, _| !frequent.contains(key));
+
+        for (key, (doc_count, bucket)) in fruit.res {
+            if harvest.frequent.contains(&key) {
+                continue;
+            }
+            let existing_bucket = harvest.res.entry(key)
+                .or_insert_with(|| (0, self.sub_agg.create_fruit()));
+            existing_bucket.0 += doc_count;
+            self.sub_agg.merge(&mut existing_bucket.1, bucket);
+            if existing_bucket.0 > harvest.max_doc_count {
+                harvest.res.remove(&key);
+                harvest.frequent.insert(&key);
+            }
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.res.values_mut().map(|(_, bucket)| bucket).collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+    };
+    ( SINGLE $(|$type:ty, $reader_fn:ident : $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident|),+ ) => { $(
+
+impl_rare_terms_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
+
+pub struct $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    ff_reader: FastFieldReader<$type>,
+    max_doc_count: u64,
+    precision: f64,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    fn new(
+        ff_reader: FastFieldReader<$type>, max_doc_count: u64, precision: f64, sub_agg: SubAgg
+    ) -> Self {
+        Self { ff_reader, max_doc_count, precision, sub_agg }
+    }
+}
+
+impl<SubAgg> SegmentAgg for $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = RareTerms<$type, SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        RareTerms::new(self.max_doc_count, self.precision)
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
+        let key = self.ff_reader.get(doc);
+        agg_value.collect(key, doc, score, &mut self.sub_agg);
+    }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for (_, bucket) in agg_value.res.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+    )* };
+    ( MULTI $(|$type:ty, $reader_fn:ident : $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident|),+ ) => { $(
+
+impl_rare_terms_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
+
+pub struct $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    ff_reader: MultiValueIntFastFieldReader<$type>,
+    max_doc_count: u64,
+    precision: f64,
+    sub_agg: SubAgg,
+    vals: Vec<$type>,
+}
+
+impl<SubAgg> $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    fn new(
+        ff_reader: MultiValueIntFastFieldReader<$type>,
+        max_doc_count: u64,
+        precision: f64,
+        sub_agg: SubAgg,
+    ) -> Self {
+        Self {
+            ff_reader,
+            max_doc_count,
+            precision,
+            sub_agg,
+            vals: vec!(),
+        }
+    }
+}
+
+impl<SubAgg> SegmentAgg for $segment_agg_struct<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = RareTerms<$type, SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        RareTerms::new(self.max_doc_count, self.precision)
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
+        self.ff_reader.get_vals(doc, &mut self.vals);
+        for &key in self.vals.iter() {
+            agg_value.collect(key, doc, score, &mut self.sub_agg);
+        }
+    }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for (_, bucket) in agg_value.res.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+    )* };
+}
+
+impl_rare_terms_agg_for_type!(
+    SINGLE
+    |u64, u64 : rare_terms_agg_u64, RareTermsAggU64, PreparedRareTermsAggU64, RareTermsSegmentAggU64|,
+    |i64, i64 : rare_terms_agg_i64, RareTermsAggI64, PreparedRareTermsAggI64, RareTermsSegmentAggI64|
+);
+
+impl_rare_terms_agg_for_type!(
+    MULTI
+    |u64, u64s : rare_terms_agg_u64s, RareTermsAggU64s, PreparedRareTermsAggU64s, RareTermsSegmentAggU64s|,
+    |i64, i64s : rare_terms_agg_i64s, RareTermsAggI64s, PreparedRareTermsAggI64s, RareTermsSegmentAggI64s|
+);
+
+#[derive(Debug)]
+pub struct RareTerms<K, T>
+where
+    K: Eq + Hash,
+{
+    res: HashMap<K, (u64, T)>,
+    frequent: ScalingBloomFilter,
+    max_doc_count: u64,
+}
+
+impl<K, T> RareTerms<K, T>
+where
+    K: Eq + Hash + Ord,
+{
+    fn new(max_doc_count: u64, precision: f64) -> Self {
+        Self {
+            res: HashMap::new(),
+            frequent: ScalingBloomFilter::new(BLOOM_FILTER_CAPACITY, precision),
+            max_doc_count,
+        }
+    }
+
+    fn collect<SubAgg>(&mut self, key: K, doc: DocId, score: Score, sub_agg: &mut SubAgg)
+    where
+        SubAgg: SegmentAgg<Fruit = T>,
+    {
+        if self.frequent.contains(&key) {
+            return;
+        }
+        match self.res.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let bucket = entry.get_mut();
+                if bucket.0 == self.max_doc_count {
+                    let (key, _) = entry.remove_entry();
+                    self.frequent.insert(&key);
+                    return;
+                }
+                bucket.0 += 1;
+                sub_agg.collect(doc, score, &mut bucket.1);
+            }
+            Entry::Vacant(entry) => {
+                if self.max_doc_count == 0 {
+                    self.frequent.insert(entry.key());
+                    return;
+                }
+                let bucket = entry.insert((1, sub_agg.create_fruit()));
+                sub_agg.collect(doc, score, &mut bucket.1);
+            }
+        }
+    }
+
+    /// Document count and sub aggregation result of the term
+    pub fn get(&self, key: &K) -> Option<&(u64, T)> {
+        self.res.get(key)
+    }
+
+    /// Rare terms ordered by document count and then by key
+    pub fn buckets(&self) -> Vec<(&K, &(u64, T))> {
+        let mut buckets = self.res.iter().collect::<Vec<_>>();
+        buckets.sort_by(|(k1, b1), (k2, b2)| b1.0.cmp(&b2.0).then_with(|| k1.cmp(k2)));
+        buckets
+    }
+}
+
+/// Scalable bloom filter that adds a new filter every time the previous one becomes full.
+///
+/// Every new filter is twice as big and twice as precise as the previous one,
+/// so the expected false positive probability of all filters stays below `precision`
+/// whatever the number of keys. Merged filters of several segments are only checked
+/// one after another, so the probability can grow up to the number of segments times `precision`.
+#[derive(Debug)]
+struct ScalingBloomFilter {
+    capacity: usize,
+    precision: f64,
+    filters: Vec<BloomFilter>,
+}
+
+impl ScalingBloomFilter {
+    fn new(capacity: usize, precision: f64) -> Self {
+        Self {
+            capacity,
+            precision,
+            filters: vec!(),
+        }
+    }
+
+    fn insert<K: Hash>(&mut self, key: &K) {
+        let hash = hash_key(key);
+        let is_full = self.filters.last()
+            .map_or(true, |filter| filter.len >= filter.capacity);
+        if is_full {
+            // Sum of the stage precisions `precision / 2^(i + 1)` is below `precision`
+            let stage = self.filters.len() as i32;
+            self.filters.push(BloomFilter::new(
+                self.capacity << stage.min(16),
+                self.precision / 2_f64.powi(stage + 1),
+            ));
+        }
+        if let Some(filter) = self.filters.last_mut() {
+            filter.insert(hash);
+        }
+    }
+
+    fn contains<K: Hash>(&self, key: &K) -> bool {
+        if self.filters.is_empty() {
+            return false;
+        }
+        let hash = hash_key(key);
+        self.filters.iter().any(|filter| filter.contains(hash))
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.filters.extend(other.filters);
+    }
+}
+
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    num_hashes: u64,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn new(capacity: usize, precision: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * precision.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let num_hashes = (num_bits / capacity as f64 * ln2).round().max(1.0);
+        Self {
+            bits: vec!(0; (num_bits / 64.0).ceil() as usize),
+            num_hashes: num_hashes as u64,
+            capacity,
+            len: 0,
+        }
+    }
+
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        // Double hashing simulates a number of independent hash functions
+        let num_bits = self.bits.len() as u64 * 64;
+        let h1 = hash;
+        let h2 = (hash >> 32) | 1;
+        (0..self.num_hashes)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn insert(&mut self, hash: u64) {
+        for pos in self.bit_positions(hash) {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+        self.len += 1;
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        self.bit_positions(hash)
+            .all(|pos| self.bits[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+}
+
+fn hash_key<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, min_agg_f64};
+    use super::{ScalingBloomFilter, rare_terms_agg_u64, rare_terms_agg_u64s};
+
+    #[test]
+    fn test_scaling_bloom_filter() {
+        let mut filter = ScalingBloomFilter::new(100, 0.01);
+        for key in 0_u64..10_000 {
+            filter.insert(&key);
+        }
+        assert_eq!(filter.filters.len(), 7);
+        assert!((0_u64..10_000).all(|key| filter.contains(&key)));
+        let false_positives = (10_000_u64..110_000)
+            .filter(|key| filter.contains(key))
+            .count();
+        // Expected rate is below 1%
+        assert!(false_positives < 1_500);
+    }
+
+    #[test]
+    fn test_rare_terms_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let tags_agg = rare_terms_agg_u64s(
+            product_index.schema.tag_ids, min_agg_f64(product_index.schema.price)
+        );
+        let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+        assert_eq!(
+            tags.buckets(),
+            vec!(
+                (&112_u64, &(1_u64, Some(9.99_f64))),
+                (&511_u64, &(1_u64, Some(100.01_f64))),
+            )
+        );
+
+        let tags_agg = rare_terms_agg_u64s(
+            product_index.schema.tag_ids, min_agg_f64(product_index.schema.price)
+        )
+            .max_doc_count(2);
+        let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+        assert_eq!(tags.get(&111_u64), Some(&(2_u64, Some(9.99_f64))));
+        assert_eq!(tags.get(&320_u64), Some(&(2_u64, Some(10.0_f64))));
+        assert_eq!(tags.get(&211_u64), None);
+        assert_eq!(tags.buckets().len(), 5);
+
+        let cat_agg = rare_terms_agg_u64(
+            product_index.schema.category_id, min_agg_f64(product_index.schema.price)
+        )
+            .max_doc_count(2)
+            .precision(0.01);
+        let cats = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(
+            cats.buckets(),
+            vec!(
+                (&1_u64, &(2_u64, Some(9.99_f64))),
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rare_terms_agg_invalid_precision() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        for &precision in &[0.0, 1.0, -0.1, f64::NAN] {
+            let agg = rare_terms_agg_u64(
+                product_index.schema.category_id, min_agg_f64(product_index.schema.price)
+            ).precision(precision);
+            assert!(searcher.agg_search(&AllQuery, &agg).is_err());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,561 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::mem;
+
+use tantivy::{DocId, Result, Score, Searcher};
+use tantivy::fastfield::{FastFieldNotAvailableError, FastFieldReader};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+
+/// Restricts the sub-aggregation to the `shard_size` best scoring documents of every segment.
+///
+/// Sampled documents are buffered and passed to the sub-aggregation in doc order
+/// when the segment is finished. Aggregations that follow the order of documents
+/// (like `filter_agg`) should only be used under the sampler when the sampler
+/// is not nested into another bucket aggregation.
+pub fn sampler_agg<SubAgg>(shard_size: usize, sub_agg: SubAgg) -> SamplerAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    SamplerAgg {
+        shard_size,
+        sub_agg,
+    }
+}
+
+pub struct SamplerAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    shard_size: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> Agg for SamplerAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = Sample<SubAgg::Fruit>;
+    type Child = PreparedSamplerAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            shard_size: self.shard_size,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+}
+
+pub struct PreparedSamplerAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    shard_size: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedSamplerAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Sample<SubAgg::Fruit>;
+    type Child = SamplerSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Sample::new(self.sub_agg.create_fruit())
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            shard_size: self.shard_size,
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        harvest.doc_count += fruit.doc_count;
+        self.sub_agg.merge(&mut harvest.sub_agg, fruit.sub_agg);
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize(&mut harvest.sub_agg)
+    }
+}
+
+pub struct SamplerSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    shard_size: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for SamplerSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Sample<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Sample::new(self.sub_agg.create_fruit())
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        fruit.buffer.push(self.shard_size, ScoredDoc { score, doc });
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        let scored_docs = fruit.buffer.take_docs();
+        fruit.replay(scored_docs, &mut self.sub_agg);
+    }
+}
+
+/// Like the `sampler_agg` but at most `max_docs_per_value` documents with the same value
+/// of the single-valued fast `field` get into the sample of a segment.
+pub fn diversified_sampler_agg<SubAgg>(
+    field: Field, max_docs_per_value: usize, shard_size: usize, sub_agg: SubAgg
+) -> DiversifiedSamplerAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    DiversifiedSamplerAgg {
+        field,
+        max_docs_per_value,
+        shard_size,
+        sub_agg,
+    }
+}
+
+pub struct DiversifiedSamplerAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    max_docs_per_value: usize,
+    shard_size: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> Agg for DiversifiedSamplerAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = Sample<SubAgg::Fruit, DiversifiedTopDocs>;
+    type Child = PreparedDiversifiedSamplerAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            field: self.field,
+            max_docs_per_value: self.max_docs_per_value,
+            shard_size: self.shard_size,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+}
+
+pub struct PreparedDiversifiedSamplerAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    field: Field,
+    max_docs_per_value: usize,
+    shard_size: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedDiversifiedSamplerAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Sample<SubAgg::Fruit, DiversifiedTopDocs>;
+    type Child = DiversifiedSamplerSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Sample::new(self.sub_agg.create_fruit())
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let ff_reader = ctx.reader.fast_fields().u64_lenient(self.field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(self.field)
+                )
+            })?;
+        Ok(Self::Child {
+            ff_reader,
+            max_docs_per_value: self.max_docs_per_value,
+            shard_size: self.shard_size,
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        harvest.doc_count += fruit.doc_count;
+        self.sub_agg.merge(&mut harvest.sub_agg, fruit.sub_agg);
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize(&mut harvest.sub_agg)
+    }
+}
+
+pub struct DiversifiedSamplerSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    ff_reader: FastFieldReader<u64>,
+    max_docs_per_value: usize,
+    shard_size: usize,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for DiversifiedSamplerSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Sample<SubAgg::Fruit, DiversifiedTopDocs>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Sample::new(self.sub_agg.create_fruit())
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        let value = self.ff_reader.get(doc);
+        fruit.buffer.push(
+            self.shard_size, self.max_docs_per_value, ScoredDoc { score, doc }, value
+        );
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        let scored_docs = fruit.buffer.take_docs();
+        fruit.replay(scored_docs, &mut self.sub_agg);
+    }
+}
+
+/// Best documents of the segment
+#[derive(Debug, Default)]
+pub struct TopDocs {
+    heap: BinaryHeap<Reverse<ScoredDoc>>,
+}
+
+impl TopDocs {
+    /// Keeps at most `size` best documents
+    fn push(&mut self, size: usize, scored_doc: ScoredDoc) {
+        if self.heap.len() < size {
+            self.heap.push(Reverse(scored_doc));
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if scored_doc > worst.0 {
+                *worst = Reverse(scored_doc);
+            }
+        }
+    }
+
+    fn take_docs(&mut self) -> Vec<ScoredDoc> {
+        self.heap.drain().map(|d| d.0).collect()
+    }
+}
+
+/// Best documents of the segment with a limited number of documents per value
+#[derive(Debug, Default)]
+pub struct DiversifiedTopDocs {
+    docs: BTreeSet<(ScoredDoc, u64)>,
+    docs_by_value: HashMap<u64, BTreeSet<ScoredDoc>>,
+}
+
+impl DiversifiedTopDocs {
+    fn push(&mut self, size: usize, max_docs_per_value: usize, scored_doc: ScoredDoc, value: u64) {
+        if let Some(value_docs) = self.docs_by_value.get_mut(&value) {
+            if value_docs.len() >= max_docs_per_value {
+                // Replaces the worst document with the same value
+                let worst = match value_docs.iter().next() {
+                    Some(&worst) if scored_doc > worst => worst,
+                    _ => return,
+                };
+                value_docs.remove(&worst);
+                value_docs.insert(scored_doc);
+                self.docs.remove(&(worst, value));
+                self.docs.insert((scored_doc, value));
+                return;
+            }
+        } else if max_docs_per_value == 0 {
+            return;
+        }
+
+        if self.docs.len() >= size {
+            let (worst, worst_value) = match self.docs.iter().next() {
+                Some(&(worst, worst_value)) if scored_doc > worst => (worst, worst_value),
+                _ => return,
+            };
+            self.docs.remove(&(worst, worst_value));
+            if let Some(worst_value_docs) = self.docs_by_value.get_mut(&worst_value) {
+                worst_value_docs.remove(&worst);
+                if worst_value_docs.is_empty() {
+                    self.docs_by_value.remove(&worst_value);
+                }
+            }
+        }
+        self.docs_by_value.entry(value).or_default().insert(scored_doc);
+        self.docs.insert((scored_doc, value));
+    }
+
+    fn take_docs(&mut self) -> Vec<ScoredDoc> {
+        self.docs_by_value.clear();
+        mem::take(&mut self.docs).into_iter().map(|(d, _)| d).collect()
+    }
+}
+
+/// Documents with higher score are greater, ties are broken by lower doc id
+#[derive(Clone, Copy, Debug)]
+struct ScoredDoc {
+    score: Score,
+    doc: DocId,
+}
+
+impl PartialEq for ScoredDoc {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScoredDoc {}
+
+impl Ord for ScoredDoc {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score)
+            .then_with(|| other.doc.cmp(&self.doc))
+    }
+}
+
+impl PartialOrd for ScoredDoc {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug)]
+pub struct Sample<T, B = TopDocs> {
+    doc_count: u64,
+    sub_agg: T,
+    buffer: B,
+    /// Sampled documents of the segment were collected into the sub-aggregation
+    replayed: bool,
+}
+
+impl<T, B> Sample<T, B>
+where
+    B: Default,
+{
+    fn new(sub_agg: T) -> Self {
+        Self {
+            doc_count: 0,
+            sub_agg,
+            buffer: B::default(),
+            replayed: false,
+        }
+    }
+
+    /// Collects sampled documents of the segment into the sub-aggregation
+    /// and finishes it, the fruit is only replayed once
+    fn replay<SubAgg>(&mut self, mut scored_docs: Vec<ScoredDoc>, sub_agg: &mut SubAgg)
+    where
+        SubAgg: SegmentAgg<Fruit = T>,
+    {
+        if self.replayed {
+            return;
+        }
+        self.replayed = true;
+        scored_docs.sort_unstable_by_key(|d| d.doc);
+        for scored_doc in scored_docs.iter() {
+            sub_agg.collect(scored_doc.doc, scored_doc.score, &mut self.sub_agg);
+        }
+        self.doc_count += scored_docs.len() as u64;
+        sub_agg.finish(&mut self.sub_agg);
+    }
+
+    /// Number of sampled documents
+    pub fn doc_count(&self) -> u64 {
+        self.doc_count
+    }
+
+    pub fn sub_agg(&self) -> &T {
+        &self.sub_agg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::{Result, Term};
+    use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, TermQuery};
+    use tantivy::schema::IndexRecordOption;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::agg::SegmentAgg;
+    use crate::{AggSearcher, count_agg, max_agg_f64, min_agg_f64, terms_agg_u64};
+    use super::{
+        DiversifiedTopDocs, Sample, ScoredDoc, TopDocs, diversified_sampler_agg, sampler_agg,
+    };
+
+    /// Counts collected documents and finish calls
+    struct FinishCountingAgg;
+
+    impl SegmentAgg for FinishCountingAgg {
+        type Fruit = (u64, u64);
+
+        fn create_fruit(&self) -> Self::Fruit {
+            (0, 0)
+        }
+
+        fn collect(&mut self, _doc: u32, _score: f32, fruit: &mut Self::Fruit) {
+            fruit.0 += 1;
+        }
+
+        fn finish(&mut self, fruit: &mut Self::Fruit) {
+            fruit.1 += 1;
+        }
+    }
+
+    #[test]
+    fn test_sample_replay() {
+        let mut sample = Sample::<_, TopDocs>::new((0, 0));
+        sample.buffer.push(2, ScoredDoc { score: 1.0, doc: 1 });
+        sample.buffer.push(2, ScoredDoc { score: f32::NAN, doc: 2 });
+        sample.buffer.push(2, ScoredDoc { score: 0.5, doc: 3 });
+
+        let scored_docs = sample.buffer.take_docs();
+        sample.replay(scored_docs, &mut FinishCountingAgg);
+        let scored_docs = sample.buffer.take_docs();
+        sample.replay(scored_docs, &mut FinishCountingAgg);
+        assert_eq!(sample.doc_count(), 2);
+        assert_eq!(sample.sub_agg(), &(2, 1));
+    }
+
+    #[test]
+    fn test_diversified_top_docs() {
+        let mut top_docs = DiversifiedTopDocs::default();
+        top_docs.push(2, 1, ScoredDoc { score: 1.0, doc: 1 }, 10);
+        top_docs.push(2, 1, ScoredDoc { score: 2.0, doc: 2 }, 20);
+        // Rejected documents don't leave empty values behind
+        top_docs.push(2, 1, ScoredDoc { score: 0.5, doc: 3 }, 30);
+        top_docs.push(2, 1, ScoredDoc { score: 0.5, doc: 4 }, 10);
+        assert_eq!(top_docs.docs_by_value.len(), 2);
+        // The worst document is evicted with its value
+        top_docs.push(2, 1, ScoredDoc { score: 3.0, doc: 5 }, 30);
+        assert_eq!(top_docs.docs_by_value.len(), 2);
+        assert!(!top_docs.docs_by_value.contains_key(&10));
+
+        let mut docs = top_docs.take_docs();
+        docs.sort_unstable_by_key(|d| d.doc);
+        assert_eq!(docs.iter().map(|d| d.doc).collect::<Vec<_>>(), vec![2, 5]);
+    }
+
+    #[test]
+    fn test_sampler_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        // All documents have the same score, so the first ones are sampled
+        let sample = searcher.agg_search(
+            &AllQuery,
+            &sampler_agg(2, (count_agg(), min_agg_f64(product_index.schema.price)))
+        )?;
+        assert_eq!(sample.doc_count(), 2);
+        assert_eq!(sample.sub_agg(), &(2_u64, Some(9.99_f64)));
+
+        let sample = searcher.agg_search(
+            &AllQuery,
+            &sampler_agg(10, (count_agg(), min_agg_f64(product_index.schema.price)))
+        )?;
+        assert_eq!(sample.doc_count(), 5);
+        assert_eq!(sample.sub_agg(), &(5_u64, Some(0.5_f64)));
+
+        // Only the 4th product matches both clauses
+        let query = BooleanQuery::from(vec!(
+            (
+                Occur::Should,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(product_index.schema.brand, "acme"),
+                    IndexRecordOption::Basic
+                )) as Box<dyn Query>
+            ),
+            (Occur::Should, Box::new(product_index.category_query(2)) as Box<dyn Query>),
+        ));
+        let sample = searcher.agg_search(
+            &query,
+            &sampler_agg(1, min_agg_f64(product_index.schema.price))
+        )?;
+        assert_eq!(sample.doc_count(), 1);
+        assert_eq!(sample.sub_agg(), &Some(50.0_f64));
+
+        let cat_samples = searcher.agg_search(
+            &query,
+            &terms_agg_u64(
+                product_index.schema.category_id,
+                sampler_agg(1, min_agg_f64(product_index.schema.price))
+            )
+        )?;
+        assert_eq!(cat_samples.get(&1).map(|s| s.sub_agg()), Some(&Some(9.99_f64)));
+        assert_eq!(cat_samples.get(&2).map(|s| s.sub_agg()), Some(&Some(50.0_f64)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diversified_sampler_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let sample = searcher.agg_search(
+            &AllQuery,
+            &diversified_sampler_agg(
+                product_index.schema.category_id, 1, 10,
+                (count_agg(), min_agg_f64(product_index.schema.price))
+            )
+        )?;
+        assert_eq!(sample.doc_count(), 2);
+        assert_eq!(sample.sub_agg(), &(2_u64, Some(0.5_f64)));
+
+        let sample = searcher.agg_search(
+            &AllQuery,
+            &diversified_sampler_agg(
+                product_index.schema.category_id, 2, 3,
+                (count_agg(), max_agg_f64(product_index.schema.price))
+            )
+        )?;
+        assert_eq!(sample.doc_count(), 3);
+        assert_eq!(sample.sub_agg(), &(3_u64, Some(10.0_f64)));
+
+        let query = BooleanQuery::from(vec!(
+            (
+                Occur::Should,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(product_index.schema.brand, "acme"),
+                    IndexRecordOption::Basic
+                )) as Box<dyn Query>
+            ),
+            (Occur::Should, Box::new(product_index.category_query(2)) as Box<dyn Query>),
+        ));
+        let sample = searcher.agg_search(
+            &query,
+            &diversified_sampler_agg(
+                product_index.schema.category_id, 1, 10,
+                (count_agg(), min_agg_f64(product_index.schema.price))
+            )
+        )?;
+        assert_eq!(sample.doc_count(), 2);
+        assert_eq!(sample.sub_agg(), &(2_u64, Some(9.99_f64)));
+
+        Ok(())
+    }
+}
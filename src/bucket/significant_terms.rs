@@ -0,0 +1,463 @@
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use tantivy::{DocId, InvertedIndexReader, Result, Score, Searcher, TantivyError, Term};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::metric::count::{CountAgg, count_agg};
+use super::terms::{
+    Terms,
+    terms_agg_i64, terms_agg_i64s, terms_agg_u64, terms_agg_u64s,
+    TermsAggI64, TermsAggI64s, TermsAggU64, TermsAggU64s,
+};
+
+/// Keys of the terms aggregation that can be looked up in the inverted index
+pub trait TermKey: Copy + Eq + Hash + Ord + Send {
+    fn to_term(self, field: Field) -> Term;
+}
+
+impl TermKey for u64 {
+    fn to_term(self, field: Field) -> Term {
+        Term::from_field_u64(field, self)
+    }
+}
+
+impl TermKey for i64 {
+    fn to_term(self, field: Field) -> Term {
+        Term::from_field_i64(field, self)
+    }
+}
+
+/// Scores how unusual the term frequency in the foreground set
+/// is comparing to the background set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignificanceHeuristic {
+    Jlh,
+    MutualInformation,
+    ChiSquare,
+    Gnd,
+}
+
+impl SignificanceHeuristic {
+    pub fn score(
+        &self, subset_freq: u64, subset_size: u64, superset_freq: u64, superset_size: u64
+    ) -> f64 {
+        if subset_freq == 0 || subset_size == 0 || superset_size == 0 {
+            return 0.0;
+        }
+        match self {
+            Self::Jlh => {
+                let subset_prob = subset_freq as f64 / subset_size as f64;
+                // Avoid division by zero when the background set is not a superset
+                let superset_prob = superset_freq.max(1) as f64 / superset_size as f64;
+                let abs_change = subset_prob - superset_prob;
+                if abs_change <= 0.0 {
+                    return 0.0;
+                }
+                abs_change * (subset_prob / superset_prob)
+            }
+            Self::MutualInformation => {
+                let f = Frequencies::new(subset_freq, subset_size, superset_freq, superset_size);
+                if !f.is_over_represented() {
+                    return 0.0;
+                }
+                let mi = |nxy: f64, nx: f64, ny: f64| {
+                    if nxy <= 0.0 || nx <= 0.0 || ny <= 0.0 {
+                        0.0
+                    } else {
+                        nxy / f.n * (f.n * nxy / (nx * ny)).log2()
+                    }
+                };
+                mi(f.n11, f.n1_, f.n_1) +
+                    mi(f.n01, f.n0_, f.n_1) +
+                    mi(f.n10, f.n1_, f.n_0) +
+                    mi(f.n00, f.n0_, f.n_0)
+            }
+            Self::ChiSquare => {
+                let f = Frequencies::new(subset_freq, subset_size, superset_freq, superset_size);
+                if !f.is_over_represented() {
+                    return 0.0;
+                }
+                let denominator = f.n_1 * f.n1_ * f.n0_ * f.n_0;
+                if denominator <= 0.0 {
+                    return 0.0;
+                }
+                f.n * (f.n11 * f.n00 - f.n10 * f.n01).powi(2) / denominator
+            }
+            Self::Gnd => {
+                let f = Frequencies::new(subset_freq, subset_size, superset_freq, superset_size);
+                if f.n1_ == f.n_1 && f.n1_ == f.n11 {
+                    return 1.0;
+                }
+                let (log_fx, log_fy) = (f.n1_.ln(), f.n_1.ln());
+                let distance = (log_fx.max(log_fy) - f.n11.ln()) /
+                    (f.n.ln() - log_fx.min(log_fy));
+                // Relevant terms have small distance
+                (-distance).exp()
+            }
+        }
+    }
+}
+
+/// Contingency table of the term presence (first index)
+/// and the foreground set membership (second index).
+/// The background set is considered as a superset of the foreground one.
+struct Frequencies {
+    n: f64,
+    n00: f64,
+    n01: f64,
+    n10: f64,
+    n11: f64,
+    n0_: f64,
+    n1_: f64,
+    n_0: f64,
+    n_1: f64,
+}
+
+impl Frequencies {
+    fn new(subset_freq: u64, subset_size: u64, superset_freq: u64, superset_size: u64) -> Self {
+        let n = superset_size as f64;
+        let n11 = subset_freq as f64;
+        let n10 = superset_freq as f64 - n11;
+        let n01 = subset_size as f64 - n11;
+        Self {
+            n,
+            n00: n - n11 - n10 - n01,
+            n01,
+            n10,
+            n11,
+            n0_: n - superset_freq as f64,
+            n1_: superset_freq as f64,
+            n_0: n - subset_size as f64,
+            n_1: subset_size as f64,
+        }
+    }
+
+    fn is_over_represented(&self) -> bool {
+        self.n_0 <= 0.0 || self.n11 / self.n_1 > self.n10 / self.n_0
+    }
+}
+
+macro_rules! impl_significant_terms_agg_for_type {
+    ( $(|$type:ty, $agg_fn:ident, $terms_agg_fn:ident, $terms_agg_struct:ident|),+ ) => { $(
+
+pub fn $agg_fn<SubAgg>(
+    field: Field, sub_agg: SubAgg
+) -> SignificantTermsAgg<$type, $terms_agg_struct<(CountAgg, SubAgg)>>
+where
+    SubAgg: Agg,
+{
+    SignificantTermsAgg {
+        field,
+        terms_agg: (count_agg(), $terms_agg_fn(field, (count_agg(), sub_agg))),
+        heuristic: SignificanceHeuristic::Jlh,
+        size: 10,
+        min_doc_count: 3,
+        key: PhantomData,
+    }
+}
+
+    )* };
+}
+
+impl_significant_terms_agg_for_type!(
+    |u64, significant_terms_agg_u64, terms_agg_u64, TermsAggU64|,
+    |i64, significant_terms_agg_i64, terms_agg_i64, TermsAggI64|,
+    |u64, significant_terms_agg_u64s, terms_agg_u64s, TermsAggU64s|,
+    |i64, significant_terms_agg_i64s, terms_agg_i64s, TermsAggI64s|
+);
+
+/// Finds terms that are more frequent among the matched documents
+/// than among all documents of the index.
+///
+/// The field must be indexed as background frequencies are fetched
+/// from the inverted index.
+pub struct SignificantTermsAgg<K, TermsAgg> {
+    field: Field,
+    terms_agg: (CountAgg, TermsAgg),
+    heuristic: SignificanceHeuristic,
+    size: usize,
+    min_doc_count: u64,
+    key: PhantomData<fn() -> K>,
+}
+
+impl<K, TermsAgg> SignificantTermsAgg<K, TermsAgg> {
+    /// Defaults to JLH score
+    pub fn heuristic(mut self, heuristic: SignificanceHeuristic) -> Self {
+        self.heuristic = heuristic;
+        self
+    }
+
+    /// Maximum number of returned terms, defaults to 10
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Minimum number of matched documents for a term, defaults to 3
+    pub fn min_doc_count(mut self, min_doc_count: u64) -> Self {
+        self.min_doc_count = min_doc_count;
+        self
+    }
+}
+
+impl<K, TermsAgg, T> Agg for SignificantTermsAgg<K, TermsAgg>
+where
+    K: TermKey,
+    TermsAgg: Agg<Fruit = Terms<K, (u64, T)>>,
+    T: Send,
+{
+    type Fruit = SignificantTerms<K, T>;
+    type Child = PreparedSignificantTermsAgg<K, <(CountAgg, TermsAgg) as Agg>::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        let field_entry = searcher.schema().get_field_entry(self.field);
+        if !field_entry.is_indexed() {
+            return Err(TantivyError::SchemaError(
+                format!("Field {} is not indexed", field_entry.name())
+            ));
+        }
+        Ok(Self::Child {
+            field: self.field,
+            inverted_indexes: searcher.segment_readers().iter()
+                .map(|reader| reader.inverted_index(self.field))
+                .collect(),
+            // Background counts come from the inverted index which includes deleted documents
+            superset_size: searcher.segment_readers().iter()
+                .map(|reader| reader.max_doc() as u64)
+                .sum(),
+            heuristic: self.heuristic,
+            size: self.size,
+            min_doc_count: self.min_doc_count,
+            terms_agg: self.terms_agg.prepare(searcher)?,
+            key: PhantomData,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.terms_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedSignificantTermsAgg<K, TermsAgg> {
+    field: Field,
+    inverted_indexes: Vec<Arc<InvertedIndexReader>>,
+    superset_size: u64,
+    heuristic: SignificanceHeuristic,
+    size: usize,
+    min_doc_count: u64,
+    terms_agg: TermsAgg,
+    key: PhantomData<fn() -> K>,
+}
+
+impl<K, TermsAgg, T> PreparedSignificantTermsAgg<K, TermsAgg>
+where
+    K: TermKey,
+    TermsAgg: PreparedAgg<Fruit = (u64, Terms<K, (u64, T)>)>,
+{
+    fn background_freq(&self, key: K) -> u64 {
+        let term = key.to_term(self.field);
+        self.inverted_indexes.iter()
+            .map(|inverted_index| inverted_index.doc_freq(&term) as u64)
+            .sum()
+    }
+}
+
+impl<K, TermsAgg, T> PreparedAgg for PreparedSignificantTermsAgg<K, TermsAgg>
+where
+    K: TermKey,
+    TermsAgg: PreparedAgg<Fruit = (u64, Terms<K, (u64, T)>)>,
+    T: Send,
+{
+    type Fruit = SignificantTerms<K, T>;
+    type Child = SignificantTermsSegmentAgg<TermsAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        SignificantTerms::new(self.terms_agg.create_fruit())
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            terms_agg: self.terms_agg.for_segment(ctx)?,
+        })
+    }
+
+    fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
+        self.terms_agg.merge(&mut acc.collected, fruit.collected);
+    }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        self.terms_agg.finalize(&mut fruit.collected)?;
+        fruit.superset_size = self.superset_size;
+
+        let subset_size = fruit.collected.0;
+        let mut buckets = fruit.collected.1.res.drain()
+            .filter(|(_, (doc_count, _))| *doc_count >= self.min_doc_count)
+            .filter_map(|(key, (doc_count, sub_agg))| {
+                let bg_count = self.background_freq(key);
+                let score = self.heuristic.score(
+                    doc_count, subset_size, bg_count, self.superset_size
+                );
+                if score > 0.0 {
+                    Some(SignificantTerm { key, doc_count, bg_count, score, sub_agg })
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        buckets.sort_by(|a, b| {
+            b.score.total_cmp(&a.score)
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        buckets.truncate(self.size);
+        fruit.buckets = buckets;
+        Ok(())
+    }
+}
+
+pub struct SignificantTermsSegmentAgg<TermsAgg> {
+    terms_agg: TermsAgg,
+}
+
+impl<K, TermsAgg, T> SegmentAgg for SignificantTermsSegmentAgg<TermsAgg>
+where
+    K: TermKey,
+    TermsAgg: SegmentAgg<Fruit = (u64, Terms<K, (u64, T)>)>,
+{
+    type Fruit = SignificantTerms<K, T>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        SignificantTerms::new(self.terms_agg.create_fruit())
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        self.terms_agg.collect(doc, score, &mut fruit.collected);
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        self.terms_agg.finish(&mut fruit.collected);
+    }
+}
+
+#[derive(Debug)]
+pub struct SignificantTerm<K, T> {
+    pub key: K,
+    /// Number of matched documents with the term
+    pub doc_count: u64,
+    /// Number of all documents with the term including deleted ones
+    pub bg_count: u64,
+    pub score: f64,
+    pub sub_agg: T,
+}
+
+#[derive(Debug)]
+pub struct SignificantTerms<K, T>
+where
+    K: Eq + Hash,
+{
+    collected: (u64, Terms<K, (u64, T)>),
+    superset_size: u64,
+    buckets: Vec<SignificantTerm<K, T>>,
+}
+
+impl<K, T> SignificantTerms<K, T>
+where
+    K: Eq + Hash,
+{
+    fn new(collected: (u64, Terms<K, (u64, T)>)) -> Self {
+        Self {
+            collected,
+            superset_size: 0,
+            buckets: vec!(),
+        }
+    }
+
+    /// Number of matched documents
+    pub fn subset_size(&self) -> u64 {
+        self.collected.0
+    }
+
+    /// Number of all documents in the index.
+    /// Like the background counts it includes deleted documents until their segments are merged.
+    pub fn superset_size(&self) -> u64 {
+        self.superset_size
+    }
+
+    /// Terms ordered by significance score
+    pub fn buckets(&self) -> &[SignificantTerm<K, T>] {
+        &self.buckets
+    }
+
+    pub fn get(&self, key: &K) -> Option<&SignificantTerm<K, T>> {
+        self.buckets.iter().find(|b| &b.key == key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64, terms_agg_u64};
+    use super::{SignificanceHeuristic, significant_terms_agg_u64s};
+
+    #[test]
+    fn test_significant_terms_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let attrs_agg = significant_terms_agg_u64s(
+            product_index.schema.attr_facets,
+            min_agg_f64(product_index.schema.price)
+        )
+            .min_doc_count(1);
+        let attrs = searcher.agg_search(&product_index.category_query(1), &attrs_agg)?;
+        assert_eq!(attrs.subset_size(), 2);
+        assert_eq!(attrs.superset_size(), 5);
+        assert_eq!(
+            attrs.buckets().iter()
+                .map(|b| (b.key, b.doc_count, b.bg_count, b.sub_agg))
+                .collect::<Vec<_>>(),
+            vec!(
+                ((2_u64 << 32) | 3, 2, 2, Some(9.99_f64)),
+                ((1_u64 << 32) | 1, 1, 1, Some(9.99_f64)),
+                ((1_u64 << 32) | 2, 1, 1, Some(10.0_f64)),
+            )
+        );
+        assert!((attrs.buckets()[0].score - 1.5).abs() < 1e-9);
+        assert!((attrs.buckets()[1].score - 0.75).abs() < 1e-9);
+
+        let attrs_agg = significant_terms_agg_u64s(
+            product_index.schema.attr_facets,
+            min_agg_f64(product_index.schema.price)
+        )
+            .heuristic(SignificanceHeuristic::Gnd)
+            .min_doc_count(2)
+            .size(1);
+        let attrs = searcher.agg_search(&product_index.category_query(1), &attrs_agg)?;
+        assert_eq!(attrs.buckets().len(), 1);
+        assert_eq!(attrs.buckets()[0].key, (2_u64 << 32) | 3);
+        assert_eq!(attrs.buckets()[0].score, 1.0);
+        assert!(attrs.get(&((1_u64 << 32) | 1)).is_none());
+
+        let cat_attrs = searcher.agg_search(
+            &AllQuery,
+            &terms_agg_u64(
+                product_index.schema.category_id,
+                significant_terms_agg_u64s(product_index.schema.attr_facets, count_agg())
+                    .min_doc_count(1)
+            )
+        )?;
+        let cat1_attrs = cat_attrs.get(&1_u64).unwrap();
+        assert_eq!(cat1_attrs.subset_size(), 2);
+        assert_eq!(cat1_attrs.superset_size(), 5);
+        assert_eq!(cat1_attrs.buckets()[0].key, (2_u64 << 32) | 3);
+
+        Ok(())
+    }
+}
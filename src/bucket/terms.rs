@@ -1,8 +1,11 @@
-use std::cmp::Reverse;
-use std::collections::{BinaryHeap, HashMap};
+use std::borrow::Borrow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
+use std::sync::Arc;
 
-use tantivy::{DocId, Result, Score, Searcher};
+use tantivy::{DocId, Result, Score, Searcher, TantivyError};
 use tantivy::fastfield::{
     FastFieldNotAvailableError,
     FastFieldReader,
@@ -11,6 +14,8 @@ use tantivy::fastfield::{
 use tantivy::schema::Field;
 
 use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::metric::MetricValue;
+use crate::missing::PresenceReader;
 
 macro_rules! impl_terms_agg_for_type {
     ( $type:ty, $reader_fn:ident : $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident ) => {
@@ -20,6 +25,9 @@ where
     SubAgg: Agg,
 {
     field: Field,
+    missing: Option<Field>,
+    options: TermsOptions<SubAgg::Fruit>,
+    include_exclude: IncludeExclude<$type>,
     sub_agg: SubAgg,
 }
 
@@ -29,10 +37,57 @@ where
 {
     $agg_struct {
         field,
+        missing: None,
+        options: TermsOptions::default(),
+        include_exclude: IncludeExclude::default(),
         sub_agg,
     }
 }
 
+impl<SubAgg> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Keeps only `size` first buckets according to the order,
+    /// by default all the buckets are returned
+    pub fn size(mut self, size: usize) -> Self {
+        self.options.size = Some(size);
+        self
+    }
+
+    /// Number of buckets kept for every segment, defaults to `size * 1.5 + 10`.
+    /// Less buckets means less memory but bigger error of the document counts.
+    pub fn shard_size(mut self, shard_size: usize) -> Self {
+        self.options.shard_size = Some(shard_size);
+        self
+    }
+
+    /// Order of the buckets, defaults to the document count descending
+    /// when the buckets are pruned and to the key ascending otherwise
+    pub fn order(mut self, order: TermsOrder<SubAgg::Fruit>) -> Self {
+        self.options.order = Some(order);
+        self
+    }
+
+    /// Removes buckets with less documents
+    pub fn min_doc_count(mut self, min_doc_count: u64) -> Self {
+        self.options.min_doc_count = min_doc_count;
+        self
+    }
+
+    /// Collects only terms from the set
+    pub fn include(mut self, terms: TermsSet<$type>) -> Self {
+        self.include_exclude.include = Some(terms);
+        self
+    }
+
+    /// Skips terms from the set
+    pub fn exclude(mut self, terms: TermsSet<$type>) -> Self {
+        self.include_exclude.exclude = Some(terms);
+        self
+    }
+}
+
 impl<SubAgg> Agg for $agg_struct<SubAgg>
 where
     SubAgg: Agg,
@@ -42,14 +97,18 @@ where
     type Child = $prepared_agg_struct<SubAgg::Child>;
 
     fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        self.include_exclude.validate()?;
         Ok(Self::Child {
             field: self.field,
+            missing: self.missing,
+            options: self.options.prepare(),
+            include_exclude: Arc::new(self.include_exclude.clone()),
             sub_agg: self.sub_agg.prepare(searcher)?,
         })
     }
 
     fn requires_scoring(&self) -> bool {
-        false
+        self.sub_agg.requires_scoring()
     }
 }
 
@@ -58,6 +117,9 @@ where
     SubAgg: PreparedAgg,
 {
     field: Field,
+    missing: Option<Field>,
+    options: TermsOptions<SubAgg::Fruit>,
+    include_exclude: Arc<IncludeExclude<$type>>,
     sub_agg: SubAgg,
 }
 
@@ -79,16 +141,24 @@ where
                     ctx.reader.schema().get_field_entry(self.field)
                 )
             })?;
-        Ok(Self::Child::new(ff_reader, self.sub_agg.for_segment(ctx)?))
+        Self::Child::new(
+            ctx,
+            ff_reader,
+            self.missing,
+            self.options.clone(),
+            self.include_exclude.clone(),
+            self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        )
     }
 
     fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
-        for (key, bucket) in fruit.res {
-            let existing_bucket = harvest.res.entry(key)
-                .or_insert_with(|| self.sub_agg.create_fruit());
+        harvest.merge(fruit, &self.sub_agg);
+    }
 
-            self.sub_agg.merge(existing_bucket, bucket);
-        }
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.res.values_mut().chain(harvest.missing.as_mut()).collect::<Vec<_>>())?;
+        self.options.finalize(harvest);
+        Ok(())
     }
 }
 
@@ -97,11 +167,27 @@ where
 
 impl_terms_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
 
+impl<SubAgg> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Collects documents without a value in the `presence_field` into a separate bucket.
+    /// The presence field must be a multi-valued fast field.
+    pub fn missing(mut self, presence_field: Field) -> Self {
+        self.missing = Some(presence_field);
+        self
+    }
+}
+
 pub struct $segment_agg_struct<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
     ff_reader: FastFieldReader<$type>,
+    presence: Option<PresenceReader>,
+    options: TermsOptions<SubAgg::Fruit>,
+    counts_docs: bool,
+    include_exclude: Arc<IncludeExclude<$type>>,
     sub_agg: SubAgg,
 }
 
@@ -109,8 +195,26 @@ impl<SubAgg> $segment_agg_struct<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
-    fn new(ff_reader: FastFieldReader<$type>, sub_agg: SubAgg) -> Self {
-        Self { ff_reader, sub_agg }
+    fn new(
+        ctx: &AggSegmentContext,
+        ff_reader: FastFieldReader<$type>,
+        missing: Option<Field>,
+        options: TermsOptions<SubAgg::Fruit>,
+        include_exclude: Arc<IncludeExclude<$type>>,
+        sub_agg: SubAgg,
+    ) -> Result<Self> {
+        let presence = match missing {
+            Some(presence_field) => Some(PresenceReader::open(ctx, presence_field)?),
+            None => None,
+        };
+        Ok(Self {
+            ff_reader,
+            presence,
+            counts_docs: options.counts_docs(),
+            options,
+            include_exclude,
+            sub_agg,
+        })
     }
 }
 
@@ -125,11 +229,30 @@ where
     }
 
     fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
-        let key = self.ff_reader.get(doc);
-        let bucket = agg_value.res.entry(key)
-            .or_insert_with(|| self.sub_agg.create_fruit());
+        let is_missing = self.presence.as_mut()
+            .map_or(false, |presence| presence.is_missing(doc));
+        let bucket = if is_missing {
+            agg_value.missing.get_or_insert_with(|| self.sub_agg.create_fruit())
+        } else {
+            let key = self.ff_reader.get(doc);
+            if !self.include_exclude.accepts(&key) {
+                return;
+            }
+            if self.counts_docs {
+                *agg_value.doc_counts.entry(key).or_insert(0) += 1;
+            }
+            agg_value.res.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit())
+        };
         self.sub_agg.collect(doc, score, bucket);
     }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for bucket in agg_value.res.values_mut().chain(agg_value.missing.as_mut()) {
+            self.sub_agg.finish(bucket);
+        }
+        self.options.finish_segment(agg_value);
+    }
 }
 
     )* };
@@ -137,11 +260,26 @@ where
 
 impl_terms_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
 
+impl<SubAgg> $agg_struct<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Collects documents without values into a separate bucket
+    pub fn missing(mut self) -> Self {
+        self.missing = Some(self.field);
+        self
+    }
+}
+
 pub struct $segment_agg_struct<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
     ff_reader: MultiValueIntFastFieldReader<$type>,
+    missing: bool,
+    options: TermsOptions<SubAgg::Fruit>,
+    counts_docs: bool,
+    include_exclude: Arc<IncludeExclude<$type>>,
     sub_agg: SubAgg,
     vals: Vec<$type>,
 }
@@ -150,12 +288,23 @@ impl<SubAgg> $segment_agg_struct<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
-    fn new(ff_reader: MultiValueIntFastFieldReader<$type>, sub_agg: SubAgg) -> Self {
-        Self {
+    fn new(
+        _: &AggSegmentContext,
+        ff_reader: MultiValueIntFastFieldReader<$type>,
+        missing: Option<Field>,
+        options: TermsOptions<SubAgg::Fruit>,
+        include_exclude: Arc<IncludeExclude<$type>>,
+        sub_agg: SubAgg,
+    ) -> Result<Self> {
+        Ok(Self {
             ff_reader,
+            missing: missing.is_some(),
+            counts_docs: options.counts_docs(),
+            options,
+            include_exclude,
             sub_agg,
             vals: vec!(),
-        }
+        })
     }
 }
 
@@ -171,12 +320,31 @@ where
 
     fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
         self.ff_reader.get_vals(doc, &mut self.vals);
+        if self.vals.is_empty() && self.missing {
+            let bucket = agg_value.missing
+                .get_or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
+            return;
+        }
         for &key in self.vals.iter() {
+            if !self.include_exclude.accepts(&key) {
+                continue;
+            }
+            if self.counts_docs {
+                *agg_value.doc_counts.entry(key).or_insert(0) += 1;
+            }
             let bucket = agg_value.res.entry(key)
                 .or_insert_with(|| self.sub_agg.create_fruit());
             self.sub_agg.collect(doc, score, bucket);
         }
     }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for bucket in agg_value.res.values_mut().chain(agg_value.missing.as_mut()) {
+            self.sub_agg.finish(bucket);
+        }
+        self.options.finish_segment(agg_value);
+    }
 }
 
     )* };
@@ -203,6 +371,9 @@ where
     SubAgg: Agg,
 {
     field: Field,
+    missing: Option<Field>,
+    options: TermsOptions<SubAgg::Fruit>,
+    include_exclude: IncludeExclude<$type>,
     sub_agg: SubAgg,
     filter: F,
 }
@@ -214,11 +385,59 @@ where
 {
     $agg_struct {
         field,
+        missing: None,
+        options: TermsOptions::default(),
+        include_exclude: IncludeExclude::default(),
         filter,
         sub_agg,
     }
 }
 
+impl<F, SubAgg> $agg_struct<F, SubAgg>
+where
+    F: Fn($type) -> bool,
+    SubAgg: Agg,
+{
+    /// Keeps only `size` first buckets according to the order,
+    /// by default all the buckets are returned
+    pub fn size(mut self, size: usize) -> Self {
+        self.options.size = Some(size);
+        self
+    }
+
+    /// Number of buckets kept for every segment, defaults to `size * 1.5 + 10`.
+    /// Less buckets means less memory but bigger error of the document counts.
+    pub fn shard_size(mut self, shard_size: usize) -> Self {
+        self.options.shard_size = Some(shard_size);
+        self
+    }
+
+    /// Order of the buckets, defaults to the document count descending
+    /// when the buckets are pruned and to the key ascending otherwise
+    pub fn order(mut self, order: TermsOrder<SubAgg::Fruit>) -> Self {
+        self.options.order = Some(order);
+        self
+    }
+
+    /// Removes buckets with less documents
+    pub fn min_doc_count(mut self, min_doc_count: u64) -> Self {
+        self.options.min_doc_count = min_doc_count;
+        self
+    }
+
+    /// Collects only terms from the set
+    pub fn include(mut self, terms: TermsSet<$type>) -> Self {
+        self.include_exclude.include = Some(terms);
+        self
+    }
+
+    /// Skips terms from the set
+    pub fn exclude(mut self, terms: TermsSet<$type>) -> Self {
+        self.include_exclude.exclude = Some(terms);
+        self
+    }
+}
+
 impl<F, SubAgg> Agg for $agg_struct<F, SubAgg>
 where
     F: Fn($type) -> bool + Sync + Copy,
@@ -229,15 +448,19 @@ where
     type Child = $prepared_agg_struct<F, SubAgg::Child>;
 
     fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        self.include_exclude.validate()?;
         Ok(Self::Child {
             field: self.field,
+            missing: self.missing,
+            options: self.options.prepare(),
+            include_exclude: Arc::new(self.include_exclude.clone()),
             filter: self.filter,
             sub_agg: self.sub_agg.prepare(searcher)?,
         })
     }
 
     fn requires_scoring(&self) -> bool {
-        false
+        self.sub_agg.requires_scoring()
     }
 }
 
@@ -247,6 +470,9 @@ where
     SubAgg: PreparedAgg,
 {
     field: Field,
+    missing: Option<Field>,
+    options: TermsOptions<SubAgg::Fruit>,
+    include_exclude: Arc<IncludeExclude<$type>>,
     filter: F,
     sub_agg: SubAgg,
 }
@@ -270,16 +496,25 @@ where
                     ctx.reader.schema().get_field_entry(self.field)
                 )
             })?;
-        Ok(Self::Child::new(ff_reader, self.sub_agg.for_segment(ctx)?, self.filter))
+        Self::Child::new(
+            ctx,
+            ff_reader,
+            self.missing,
+            self.options.clone(),
+            self.include_exclude.clone(),
+            self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+            self.filter,
+        )
     }
 
     fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
-        for (key, bucket) in fruit.res {
-            let existing_bucket = harvest.res.entry(key)
-                .or_insert_with(|| self.sub_agg.create_fruit());
+        harvest.merge(fruit, &self.sub_agg);
+    }
 
-            self.sub_agg.merge(existing_bucket, bucket);
-        }
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.res.values_mut().chain(harvest.missing.as_mut()).collect::<Vec<_>>())?;
+        self.options.finalize(harvest);
+        Ok(())
     }
 }
 
@@ -288,12 +523,29 @@ where
 
 impl_filtered_terms_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
 
+impl<F, SubAgg> $agg_struct<F, SubAgg>
+where
+    F: Fn($type) -> bool,
+    SubAgg: Agg,
+{
+    /// Collects documents without a value in the `presence_field` into a separate bucket.
+    /// The presence field must be a multi-valued fast field.
+    pub fn missing(mut self, presence_field: Field) -> Self {
+        self.missing = Some(presence_field);
+        self
+    }
+}
+
 pub struct $segment_agg_struct<F, SubAgg>
 where
     F: Fn($type) -> bool,
     SubAgg: SegmentAgg,
 {
     ff_reader: FastFieldReader<$type>,
+    presence: Option<PresenceReader>,
+    options: TermsOptions<SubAgg::Fruit>,
+    counts_docs: bool,
+    include_exclude: Arc<IncludeExclude<$type>>,
     filter: F,
     sub_agg: SubAgg,
 }
@@ -303,8 +555,28 @@ where
     F: Fn($type) -> bool,
     SubAgg: SegmentAgg,
 {
-    fn new(ff_reader: FastFieldReader<$type>, sub_agg: SubAgg, filter: F) -> Self {
-        Self { ff_reader, filter, sub_agg }
+    fn new(
+        ctx: &AggSegmentContext,
+        ff_reader: FastFieldReader<$type>,
+        missing: Option<Field>,
+        options: TermsOptions<SubAgg::Fruit>,
+        include_exclude: Arc<IncludeExclude<$type>>,
+        sub_agg: SubAgg,
+        filter: F,
+    ) -> Result<Self> {
+        let presence = match missing {
+            Some(presence_field) => Some(PresenceReader::open(ctx, presence_field)?),
+            None => None,
+        };
+        Ok(Self {
+            ff_reader,
+            presence,
+            counts_docs: options.counts_docs(),
+            options,
+            include_exclude,
+            filter,
+            sub_agg,
+        })
     }
 }
 
@@ -320,14 +592,30 @@ where
     }
 
     fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
-        let key = self.ff_reader.get(doc);
-        if !(self.filter)(key) {
-            return;
-        }
-        let bucket = agg_value.res.entry(key)
-            .or_insert_with(|| self.sub_agg.create_fruit());
+        let is_missing = self.presence.as_mut()
+            .map_or(false, |presence| presence.is_missing(doc));
+        let bucket = if is_missing {
+            agg_value.missing.get_or_insert_with(|| self.sub_agg.create_fruit())
+        } else {
+            let key = self.ff_reader.get(doc);
+            if !(self.filter)(key) || !self.include_exclude.accepts(&key) {
+                return;
+            }
+            if self.counts_docs {
+                *agg_value.doc_counts.entry(key).or_insert(0) += 1;
+            }
+            agg_value.res.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit())
+        };
         self.sub_agg.collect(doc, score, bucket);
     }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for bucket in agg_value.res.values_mut().chain(agg_value.missing.as_mut()) {
+            self.sub_agg.finish(bucket);
+        }
+        self.options.finish_segment(agg_value);
+    }
 }
 
     )* };
@@ -335,12 +623,28 @@ where
 
 impl_filtered_terms_agg_for_type!($type, $reader_fn : $agg_fn, $agg_struct, $prepared_agg_struct, $segment_agg_struct);
 
+impl<F, SubAgg> $agg_struct<F, SubAgg>
+where
+    F: Fn($type) -> bool,
+    SubAgg: Agg,
+{
+    /// Collects documents without values into a separate bucket
+    pub fn missing(mut self) -> Self {
+        self.missing = Some(self.field);
+        self
+    }
+}
+
 pub struct $segment_agg_struct<F, SubAgg>
 where
     F: Fn($type) -> bool,
     SubAgg: SegmentAgg,
 {
     ff_reader: MultiValueIntFastFieldReader<$type>,
+    missing: bool,
+    options: TermsOptions<SubAgg::Fruit>,
+    counts_docs: bool,
+    include_exclude: Arc<IncludeExclude<$type>>,
     filter: F,
     sub_agg: SubAgg,
     vals: Vec<$type>,
@@ -351,13 +655,25 @@ where
     F: Fn($type) -> bool,
     SubAgg: SegmentAgg,
 {
-    fn new(ff_reader: MultiValueIntFastFieldReader<$type>, sub_agg: SubAgg, filter: F) -> Self {
-        Self {
+    fn new(
+        _: &AggSegmentContext,
+        ff_reader: MultiValueIntFastFieldReader<$type>,
+        missing: Option<Field>,
+        options: TermsOptions<SubAgg::Fruit>,
+        include_exclude: Arc<IncludeExclude<$type>>,
+        sub_agg: SubAgg,
+        filter: F,
+    ) -> Result<Self> {
+        Ok(Self {
             ff_reader,
+            missing: missing.is_some(),
+            counts_docs: options.counts_docs(),
+            options,
+            include_exclude,
             filter,
             sub_agg,
             vals: vec!(),
-        }
+        })
     }
 }
 
@@ -374,15 +690,31 @@ where
 
     fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
         self.ff_reader.get_vals(doc, &mut self.vals);
+        if self.vals.is_empty() && self.missing {
+            let bucket = agg_value.missing
+                .get_or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
+            return;
+        }
         for &key in self.vals.iter() {
-            if !(self.filter)(key) {
+            if !(self.filter)(key) || !self.include_exclude.accepts(&key) {
                 continue;
             }
+            if self.counts_docs {
+                *agg_value.doc_counts.entry(key).or_insert(0) += 1;
+            }
             let bucket = agg_value.res.entry(key)
                 .or_insert_with(|| self.sub_agg.create_fruit());
             self.sub_agg.collect(doc, score, bucket);
         }
     }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        for bucket in agg_value.res.values_mut().chain(agg_value.missing.as_mut()) {
+            self.sub_agg.finish(bucket);
+        }
+        self.options.finish_segment(agg_value);
+    }
 }
 
     )* };
@@ -400,28 +732,207 @@ impl_filtered_terms_agg_for_type!(
     |i64, i64s : filtered_terms_agg_i64s, FilteredTermsAggI64s, PreparedFilteredTermsAggI64s, FilteredTermsSegmentAggI64s|
 );
 
+/// Set of terms for include and exclude options of the terms aggregations
+#[derive(Clone, Debug)]
+pub enum TermsSet<K> {
+    Values(HashSet<K>),
+    /// Terms between `from` inclusive and `to` exclusive
+    Range { from: Option<K>, to: Option<K> },
+    /// Splits terms into `num_partitions` groups by hash,
+    /// so huge number of terms can be aggregated with several requests
+    Partition { partition: u64, num_partitions: u64 },
+}
+
+impl<K> TermsSet<K>
+where
+    K: PartitionKey + Eq + Hash + Ord,
+{
+    fn contains(&self, key: &K) -> bool {
+        match self {
+            TermsSet::Values(values) => values.contains(key),
+            TermsSet::Range { from, to } => {
+                from.as_ref().map_or(true, |from| key >= from) &&
+                    to.as_ref().map_or(true, |to| key < to)
+            }
+            TermsSet::Partition { partition, num_partitions } => {
+                key.partition_hash() % num_partitions == *partition
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self {
+            TermsSet::Partition { num_partitions: 0, .. } => {
+                Err(TantivyError::InvalidArgument(
+                    "Number of partitions must be positive".to_string()
+                ))
+            }
+            TermsSet::Partition { partition, num_partitions } if partition >= num_partitions => {
+                Err(TantivyError::InvalidArgument(format!(
+                    "Partition {} must be less than the number of partitions {}",
+                    partition, num_partitions
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub trait PartitionKey {
+    /// Hash that is stable between program runs
+    fn partition_hash(&self) -> u64;
+}
+
+impl PartitionKey for u64 {
+    fn partition_hash(&self) -> u64 {
+        // Finalization mix of MurmurHash3
+        let mut h = *self;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^= h >> 33;
+        h
+    }
+}
+
+impl PartitionKey for i64 {
+    fn partition_hash(&self) -> u64 {
+        (*self as u64).partition_hash()
+    }
+}
+
+impl PartitionKey for String {
+    fn partition_hash(&self) -> u64 {
+        // FNV-1a
+        let h = self.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+        h.partition_hash()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct IncludeExclude<K> {
+    pub(crate) include: Option<TermsSet<K>>,
+    pub(crate) exclude: Option<TermsSet<K>>,
+}
+
+impl<K> Default for IncludeExclude<K> {
+    fn default() -> Self {
+        Self {
+            include: None,
+            exclude: None,
+        }
+    }
+}
+
+impl<K> IncludeExclude<K>
+where
+    K: PartitionKey + Eq + Hash + Ord,
+{
+    pub(crate) fn accepts(&self, key: &K) -> bool {
+        self.include.as_ref().map_or(true, |include| include.contains(key)) &&
+            !self.exclude.as_ref().map_or(false, |exclude| exclude.contains(key))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none()
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        for terms in self.include.iter().chain(self.exclude.iter()) {
+            terms.validate()?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Terms<K, T>
 where
     K: Eq + Hash,
 {
-    res: HashMap<K, T>,
+    pub(crate) res: HashMap<K, T>,
+    pub(crate) missing: Option<T>,
+    pub(crate) doc_counts: HashMap<K, u64>,
+    pub(crate) doc_count_error_upper_bound: u64,
+    pub(crate) sum_other_doc_count: u64,
+    pub(crate) order: Option<TermsOrder<T>>,
 }
 
 impl<T, K> Terms<K, T>
 where
     K: Eq + Hash + Ord,
 {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
-            res: HashMap::new()
+            res: HashMap::new(),
+            missing: None,
+            doc_counts: HashMap::new(),
+            doc_count_error_upper_bound: 0,
+            sum_other_doc_count: 0,
+            order: None,
         }
     }
 
-    pub fn get(&self, key: &K) -> Option<&T> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&T>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
         self.res.get(key)
     }
 
+    pub fn missing(&self) -> Option<&T> {
+        self.missing.as_ref()
+    }
+
+    /// Number of documents in the bucket. It is only counted by the `terms_agg_*` aggregations
+    /// when the buckets are pruned by `size` or `min_doc_count`, or ordered by the count.
+    pub fn doc_count<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.doc_counts.get(key).copied()
+    }
+
+    /// Maximum number of documents that could be missed
+    /// from a bucket count due to the `shard_size` pruning
+    pub fn doc_count_error_upper_bound(&self) -> u64 {
+        self.doc_count_error_upper_bound
+    }
+
+    /// Number of documents in the buckets that were pruned
+    pub fn sum_other_doc_count(&self) -> u64 {
+        self.sum_other_doc_count
+    }
+
+    /// Merges buckets and document counts of another segment
+    pub(crate) fn merge<A>(&mut self, fruit: Self, sub_agg: &A)
+    where
+        A: PreparedAgg<Fruit = T>,
+    {
+        for (key, doc_count) in fruit.doc_counts {
+            *self.doc_counts.entry(key).or_insert(0) += doc_count;
+        }
+        self.doc_count_error_upper_bound += fruit.doc_count_error_upper_bound;
+        self.sum_other_doc_count += fruit.sum_other_doc_count;
+        for (key, bucket) in fruit.res {
+            let existing_bucket = self.res.entry(key)
+                .or_insert_with(|| sub_agg.create_fruit());
+
+            sub_agg.merge(existing_bucket, bucket);
+        }
+        if let Some(missing_bucket) = fruit.missing {
+            let existing_bucket = self.missing
+                .get_or_insert_with(|| sub_agg.create_fruit());
+
+            sub_agg.merge(existing_bucket, missing_bucket);
+        }
+    }
+
     pub fn top_k<'a, F, U>(&'a self, k: usize, mut sort_by: F) -> Vec<(&'a K, &'a T)>
     where
         F: FnMut(&'a T) -> U,
@@ -457,6 +968,222 @@ where
     }
 }
 
+impl<T, K> Terms<K, T>
+where
+    K: Clone + Eq + Hash + Ord,
+{
+    /// Buckets in the order of the `terms_agg_*` aggregation, they are sorted on every call.
+    /// Buckets are ordered by key when there is no order.
+    pub fn buckets(&self) -> Vec<(&K, &T)> {
+        let mut buckets = self.res.iter().collect::<Vec<_>>();
+        match self.order.as_ref() {
+            Some(order) => buckets.sort_by(|b1, b2| self.compare(order, *b1, *b2)),
+            None => buckets.sort_by_key(|&(key, _)| key),
+        }
+        buckets
+    }
+
+    fn compare(&self, order: &TermsOrder<T>, (k1, b1): (&K, &T), (k2, b2): (&K, &T)) -> Ordering {
+        order.compare(
+            (k1, self.doc_counts.get(k1).copied().unwrap_or(0), b1),
+            (k2, self.doc_counts.get(k2).copied().unwrap_or(0), b2),
+        )
+    }
+
+    /// Keeps `size` first buckets according to the order.
+    /// Returns the highest document count of the removed buckets.
+    pub(crate) fn prune(&mut self, size: usize, order: &TermsOrder<T>) -> u64 {
+        if self.res.len() <= size {
+            return 0;
+        }
+        let mut keys = self.res.keys().cloned().collect::<Vec<_>>();
+        keys.select_nth_unstable_by(size, |k1, k2| {
+            self.compare(order, (k1, &self.res[k1]), (k2, &self.res[k2]))
+        });
+        let mut max_pruned_doc_count = 0;
+        for key in &keys[size..] {
+            self.res.remove(key);
+            let doc_count = self.doc_counts.remove(key).unwrap_or(0);
+            self.sum_other_doc_count += doc_count;
+            max_pruned_doc_count = max_pruned_doc_count.max(doc_count);
+        }
+        max_pruned_doc_count
+    }
+}
+
+/// Size, order and document count options of the terms aggregations
+pub(crate) struct TermsOptions<T> {
+    pub(crate) size: Option<usize>,
+    pub(crate) shard_size: Option<usize>,
+    pub(crate) order: Option<TermsOrder<T>>,
+    pub(crate) min_doc_count: u64,
+}
+
+impl<T> Clone for TermsOptions<T> {
+    fn clone(&self) -> Self {
+        Self {
+            size: self.size,
+            shard_size: self.shard_size,
+            order: self.order.clone(),
+            min_doc_count: self.min_doc_count,
+        }
+    }
+}
+
+impl<T> Default for TermsOptions<T> {
+    fn default() -> Self {
+        Self {
+            size: None,
+            shard_size: None,
+            order: None,
+            min_doc_count: 1,
+        }
+    }
+}
+
+impl<T> TermsOptions<T> {
+    /// Options with the default shard size
+    pub(crate) fn prepare(&self) -> Self {
+        let shard_size = self.shard_size
+            .or_else(|| self.size.map(|size| size + size / 2 + 10))
+            .map(|shard_size| shard_size.max(self.size.unwrap_or(0)));
+        Self { shard_size, ..self.clone() }
+    }
+
+    /// Documents are only counted when the counts are needed to prune or order buckets
+    pub(crate) fn counts_docs(&self) -> bool {
+        self.shard_size.is_some() ||
+            self.min_doc_count > 1 ||
+            matches!(self.order, Some(TermsOrder::Count(_)))
+    }
+
+    /// Pruned buckets are ordered by the document count descending by default
+    fn effective_order(&self) -> Option<TermsOrder<T>> {
+        self.order.clone().or_else(|| {
+            self.shard_size.map(|_| TermsOrder::Count(Direction::Desc))
+        })
+    }
+
+    /// Keeps `shard_size` buckets of a segment
+    pub(crate) fn finish_segment<K>(&self, terms: &mut Terms<K, T>)
+    where
+        K: Clone + Eq + Hash + Ord,
+    {
+        if let (Some(shard_size), Some(order)) = (self.shard_size, self.effective_order()) {
+            let max_pruned_doc_count = terms.prune(shard_size, &order);
+            terms.doc_count_error_upper_bound += max_pruned_doc_count;
+        }
+    }
+
+    pub(crate) fn finalize<K>(&self, terms: &mut Terms<K, T>)
+    where
+        K: Clone + Eq + Hash + Ord,
+    {
+        if self.min_doc_count > 1 {
+            let min_doc_count = self.min_doc_count;
+            let Terms { res, doc_counts, sum_other_doc_count, .. } = terms;
+            doc_counts.retain(|key, &mut doc_count| {
+                if doc_count >= min_doc_count {
+                    return true;
+                }
+                res.remove(key);
+                *sum_other_doc_count += doc_count;
+                false
+            });
+        }
+        terms.order = self.effective_order();
+        if let (Some(size), Some(order)) = (self.size, terms.order.clone()) {
+            terms.prune(size, &order);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+impl Direction {
+    fn apply(self, ordering: Ordering) -> Ordering {
+        match self {
+            Direction::Asc => ordering,
+            Direction::Desc => ordering.reverse(),
+        }
+    }
+}
+
+/// Order of the terms buckets. Ties are resolved by key ascending.
+pub enum TermsOrder<T> {
+    Count(Direction),
+    Key(Direction),
+    /// Orders by a metric of the sub aggregation, buckets without a metric go last
+    SubAgg(fn(&T) -> Option<f64>, Direction),
+    /// Orders by a metric addressed by a path, see `TermsOrder::metric`
+    Metric(MetricFn<T>, Direction),
+}
+
+pub type MetricFn<T> = Arc<dyn Fn(&T) -> Option<f64> + Send + Sync>;
+
+impl<T> TermsOrder<T>
+where
+    T: MetricValue + 'static,
+{
+    /// Orders by a metric of the sub aggregation addressed by the `path`,
+    /// see `MetricValue`. Buckets without a metric go last.
+    pub fn metric(path: &str, direction: Direction) -> Self {
+        let path = path.to_string();
+        TermsOrder::Metric(Arc::new(move |bucket: &T| bucket.metric_value(&path)), direction)
+    }
+}
+
+impl<T> Clone for TermsOrder<T> {
+    fn clone(&self) -> Self {
+        match self {
+            TermsOrder::Count(direction) => TermsOrder::Count(*direction),
+            TermsOrder::Key(direction) => TermsOrder::Key(*direction),
+            TermsOrder::SubAgg(metric, direction) => TermsOrder::SubAgg(*metric, *direction),
+            TermsOrder::Metric(metric, direction) => TermsOrder::Metric(metric.clone(), *direction),
+        }
+    }
+}
+
+impl<T> fmt::Debug for TermsOrder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TermsOrder::Count(direction) => f.debug_tuple("Count").field(direction).finish(),
+            TermsOrder::Key(direction) => f.debug_tuple("Key").field(direction).finish(),
+            TermsOrder::SubAgg(_, direction) => f.debug_tuple("SubAgg").field(direction).finish(),
+            TermsOrder::Metric(_, direction) => f.debug_tuple("Metric").field(direction).finish(),
+        }
+    }
+}
+
+impl<T> TermsOrder<T> {
+    fn compare<K: Ord>(&self, (k1, c1, b1): (&K, u64, &T), (k2, c2, b2): (&K, u64, &T)) -> Ordering {
+        let ordering = match self {
+            TermsOrder::Count(direction) => direction.apply(c1.cmp(&c2)),
+            TermsOrder::Key(direction) => direction.apply(k1.cmp(k2)),
+            TermsOrder::SubAgg(metric, direction) => {
+                compare_metrics(metric(b1), metric(b2), *direction)
+            }
+            TermsOrder::Metric(metric, direction) => {
+                compare_metrics(metric(b1), metric(b2), *direction)
+            }
+        };
+        ordering.then_with(|| k1.cmp(k2))
+    }
+}
+
+fn compare_metrics(v1: Option<f64>, v2: Option<f64>, direction: Direction) -> Ordering {
+    match (v1, v2) {
+        (Some(v1), Some(v2)) => direction.apply(v1.total_cmp(&v2)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::cmp::Reverse;
@@ -466,8 +1193,11 @@ mod tests {
 
     use test_fixtures::ProductIndex;
 
-    use crate::{AggSearcher, count_agg, min_agg_f64};
-    use super::{filtered_terms_agg_u64, terms_agg_u64};
+    use crate::{AggSearcher, count_agg, min_agg_f64, percentiles_agg_f64};
+    use super::{
+        Direction, TermsOrder, TermsSet,
+        filtered_terms_agg_u64, filtered_terms_agg_u64s, terms_agg_u64, terms_agg_u64s,
+    };
 
     #[test]
     fn test_empty_terms_agg() -> Result<()> {
@@ -542,6 +1272,209 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_terms_agg_missing() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let attrs_agg = terms_agg_u64s(
+            product_index.schema.attr_facets, count_agg()
+        ).missing();
+        let attr_counts = searcher.agg_search(&AllQuery, &attrs_agg)?;
+        assert_eq!(attr_counts.get(&((1_u64 << 32) | 1)), Some(&1_u64));
+        assert_eq!(attr_counts.get(&((2_u64 << 32) | 3)), Some(&2_u64));
+        assert_eq!(attr_counts.missing(), Some(&3_u64));
+
+        let cat_agg = terms_agg_u64(
+            product_index.schema.category_id, count_agg()
+        ).missing(product_index.schema.attr_facets);
+        let cat_counts = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(cat_counts.get(&1_u64), Some(&2_u64));
+        assert_eq!(cat_counts.get(&2_u64), None);
+        assert_eq!(cat_counts.missing(), Some(&3_u64));
+
+        let cat_agg = terms_agg_u64(product_index.schema.category_id, count_agg());
+        let cat_counts = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(cat_counts.missing(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_terms_agg_size() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let tags_agg = terms_agg_u64s(product_index.schema.tag_ids, count_agg())
+            .size(2);
+        let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+        assert_eq!(tags.get(&211_u64), Some(&3_u64));
+        assert_eq!(tags.doc_count(&211_u64), Some(3_u64));
+        assert_eq!(tags.top_k(10, |b| b).len(), 2);
+        assert_eq!(tags.sum_other_doc_count(), 6);
+        assert_eq!(tags.doc_count_error_upper_bound(), 0);
+
+        // Depending on the number of segments the counts can be inaccurate
+        let tags_agg = terms_agg_u64s(product_index.schema.tag_ids, count_agg())
+            .size(1)
+            .shard_size(1);
+        let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+        let (&top_tag, &top_count) = tags.top_k(1, |b| b)[0];
+        assert_eq!(tags.doc_count(&top_tag), Some(top_count));
+        assert_eq!(top_count + tags.sum_other_doc_count(), 11);
+        assert!(tags.doc_count(&211_u64).unwrap_or(0) + tags.doc_count_error_upper_bound() >= 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_terms_agg_order() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let cat_agg = terms_agg_u64(
+            product_index.schema.category_id,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let cats = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(
+            cats.buckets(),
+            vec!(
+                (&1_u64, &(2_u64, Some(9.99_f64))),
+                (&2_u64, &(3_u64, Some(0.5_f64))),
+            )
+        );
+        assert_eq!(cats.doc_count(&1_u64), None);
+
+        let cat_agg = terms_agg_u64(
+            product_index.schema.category_id,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        )
+            .order(TermsOrder::Count(Direction::Desc));
+        let cats = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(
+            cats.buckets(),
+            vec!(
+                (&2_u64, &(3_u64, Some(0.5_f64))),
+                (&1_u64, &(2_u64, Some(9.99_f64))),
+            )
+        );
+
+        let cat_agg = terms_agg_u64(
+            product_index.schema.category_id,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        )
+            .order(TermsOrder::SubAgg(|b| b.1, Direction::Desc));
+        let cats = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(
+            cats.buckets(),
+            vec!(
+                (&1_u64, &(2_u64, Some(9.99_f64))),
+                (&2_u64, &(3_u64, Some(0.5_f64))),
+            )
+        );
+
+        let tags_agg = terms_agg_u64s(product_index.schema.tag_ids, count_agg())
+            .order(TermsOrder::Key(Direction::Asc))
+            .min_doc_count(2)
+            .size(3);
+        let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+        assert_eq!(
+            tags.buckets(),
+            vec!(
+                (&111_u64, &2_u64),
+                (&211_u64, &3_u64),
+                (&311_u64, &2_u64),
+            )
+        );
+        // Documents of the buckets removed by `min_doc_count` and `size`
+        assert_eq!(tags.sum_other_doc_count(), 4);
+        assert_eq!(tags.doc_count(&112_u64), None);
+        assert_eq!(tags.doc_count(&320_u64), None);
+
+        let cat_agg = terms_agg_u64(
+            product_index.schema.category_id,
+            (
+                count_agg(),
+                (min_agg_f64(product_index.schema.price), percentiles_agg_f64(product_index.schema.price)),
+            )
+        )
+            .order(TermsOrder::metric("1.0", Direction::Asc));
+        let cats = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(
+            cats.buckets().into_iter().map(|(&cat, _)| cat).collect::<Vec<_>>(),
+            vec!(2_u64, 1_u64)
+        );
+
+        let cat_agg = terms_agg_u64(
+            product_index.schema.category_id,
+            (
+                count_agg(),
+                (min_agg_f64(product_index.schema.price), percentiles_agg_f64(product_index.schema.price)),
+            )
+        )
+            .order(TermsOrder::metric("1.1.0.5", Direction::Desc))
+            .size(1);
+        let cats = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(
+            cats.buckets().into_iter().map(|(&cat, _)| cat).collect::<Vec<_>>(),
+            vec!(2_u64)
+        );
+        assert_eq!(cats.sum_other_doc_count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_terms_agg_include_exclude() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+
+        let searcher = product_index.reader.searcher();
+
+        let tags_agg = terms_agg_u64s(product_index.schema.tag_ids, count_agg())
+            .include(TermsSet::Values(vec!(111, 211, 999).into_iter().collect()))
+            .exclude(TermsSet::Range { from: Some(200), to: Some(300) });
+        let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+        assert_eq!(tags.buckets(), vec!((&111_u64, &2_u64)));
+
+        let cat_agg = terms_agg_u64(product_index.schema.category_id, count_agg())
+            .include(TermsSet::Range { from: Some(2), to: None });
+        let cats = searcher.agg_search(&AllQuery, &cat_agg)?;
+        assert_eq!(cats.buckets(), vec!((&2_u64, &3_u64)));
+
+        let num_partitions = 3;
+        let mut partitioned_tags = vec!();
+        for partition in 0..num_partitions {
+            let tags_agg = terms_agg_u64s(product_index.schema.tag_ids, count_agg())
+                .include(TermsSet::Partition { partition, num_partitions });
+            let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+            partitioned_tags.extend(tags.buckets().into_iter().map(|(&tag, &count)| (tag, count)));
+        }
+        partitioned_tags.sort_unstable();
+        assert_eq!(
+            partitioned_tags,
+            vec!((111, 2), (112, 1), (211, 3), (311, 2), (320, 2), (511, 1))
+        );
+
+        let tags_agg = terms_agg_u64s(product_index.schema.tag_ids, count_agg())
+            .include(TermsSet::Partition { partition: 0, num_partitions: 0 });
+        assert!(searcher.agg_search(&AllQuery, &tags_agg).is_err());
+
+        let tags_agg = filtered_terms_agg_u64s(product_index.schema.tag_ids, count_agg(), |tag| tag < 300)
+            .exclude(TermsSet::Values(vec!(211).into_iter().collect()));
+        let tags = searcher.agg_search(&AllQuery, &tags_agg)?;
+        assert_eq!(tags.buckets(), vec!((&111_u64, &2_u64), (&112_u64, &1_u64)));
+
+        Ok(())
+    }
+
     #[test]
     fn test_filtered_terms_agg() -> Result<()> {
         let mut product_index = ProductIndex::create_in_ram(3)?;
@@ -566,6 +1499,36 @@ mod tests {
             cat2_bucket,
             Some(&(3u64, Some(0.5_f64)))
         );
+        assert_eq!(cat_counts.missing(), None);
+
+        let cat_agg = filtered_terms_agg_u64(
+            product_index.schema.category_id,
+            count_agg(),
+            |cat_id| cat_id % 2 == even
+        ).missing(product_index.schema.attr_facets);
+        let cat_counts = searcher.agg_search(&AllQuery,  &cat_agg)?;
+        assert_eq!(cat_counts.get(&1_u64), None);
+        assert_eq!(cat_counts.get(&2_u64), None);
+        assert_eq!(cat_counts.missing(), Some(&3_u64));
+
+        let tags_agg = filtered_terms_agg_u64s(
+            product_index.schema.attr_facets,
+            count_agg(),
+            |attr| attr >> 32 == 2
+        ).missing();
+        let attrs = searcher.agg_search(&AllQuery,  &tags_agg)?;
+        assert_eq!(attrs.buckets(), vec!((&((2_u64 << 32) | 3), &2_u64)));
+        assert_eq!(attrs.missing(), Some(&3_u64));
+
+        let tags_agg = filtered_terms_agg_u64s(
+            product_index.schema.tag_ids,
+            count_agg(),
+            |tag| tag < 300
+        ).size(1);
+        let tags = searcher.agg_search(&AllQuery,  &tags_agg)?;
+        assert_eq!(tags.buckets(), vec!((&211_u64, &3_u64)));
+        assert_eq!(tags.doc_count(&211_u64), Some(3_u64));
+        assert_eq!(tags.sum_other_doc_count(), 3);
 
         Ok(())
     }
@@ -0,0 +1,226 @@
+use std::hash::Hash;
+
+use tantivy::{DocId, Result as TantivyResult, Score, Searcher};
+use tantivy::fastfield::FastFieldNotAvailableError;
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use super::terms::Terms;
+
+/// Buckets documents by keys calculated from fast fields.
+///
+/// `ff_reader_fetcher` opens fast field readers of a segment, `key` calculates
+/// the bucket key of a document from them. Documents with the `None` key are skipped.
+pub fn terms_by_agg<FFReaderFetcher, FFReader, KeyFn, K, SubAgg>(
+    ff_reader_fetcher: FFReaderFetcher, key: KeyFn, sub_agg: SubAgg
+) -> TermsByAgg<FFReaderFetcher, FFReader, KeyFn, K, SubAgg>
+where
+    FFReaderFetcher: Fn(&AggSegmentContext) -> Result<FFReader, Field>,
+    KeyFn: Fn(&FFReader, DocId) -> Option<K>,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: Agg,
+{
+    TermsByAgg {
+        ff_reader_fetcher, key, sub_agg
+    }
+}
+
+pub struct TermsByAgg<FFReaderFetcher, FFReader, KeyFn, K, SubAgg>
+where
+    FFReaderFetcher: Fn(&AggSegmentContext) -> Result<FFReader, Field>,
+    KeyFn: Fn(&FFReader, DocId) -> Option<K>,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: Agg,
+{
+    ff_reader_fetcher: FFReaderFetcher,
+    key: KeyFn,
+    sub_agg: SubAgg,
+}
+
+impl<FFReaderFetcher, FFReader, KeyFn, K, SubAgg> Agg for TermsByAgg<FFReaderFetcher, FFReader, KeyFn, K, SubAgg>
+where
+    FFReaderFetcher: Fn(&AggSegmentContext) -> Result<FFReader, Field> + Sync + Copy,
+    KeyFn: Fn(&FFReader, DocId) -> Option<K> + Sync + Copy,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: Agg,
+{
+    type Fruit = Terms<K, SubAgg::Fruit>;
+    type Child = TermsByPreparedAgg<FFReaderFetcher, FFReader, KeyFn, K, SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> TantivyResult<Self::Child> {
+        Ok(TermsByPreparedAgg {
+            ff_reader_fetcher: self.ff_reader_fetcher,
+            key: self.key,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct TermsByPreparedAgg<FFReaderFetcher, FFReader, KeyFn, K, SubAgg>
+where
+    FFReaderFetcher: Fn(&AggSegmentContext) -> Result<FFReader, Field>,
+    KeyFn: Fn(&FFReader, DocId) -> Option<K>,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: PreparedAgg,
+{
+    ff_reader_fetcher: FFReaderFetcher,
+    key: KeyFn,
+    sub_agg: SubAgg,
+}
+
+impl<FFReaderFetcher, FFReader, KeyFn, K, SubAgg> PreparedAgg for TermsByPreparedAgg<FFReaderFetcher, FFReader, KeyFn, K, SubAgg>
+where
+    FFReaderFetcher: Fn(&AggSegmentContext) -> Result<FFReader, Field> + Sync,
+    KeyFn: Fn(&FFReader, DocId) -> Option<K> + Sync + Copy,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: PreparedAgg,
+{
+    type Fruit = Terms<K, SubAgg::Fruit>;
+    type Child = TermsBySegmentAgg<FFReader, KeyFn, K, SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Terms::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> TantivyResult<Self::Child> {
+        let ff_reader = (self.ff_reader_fetcher)(ctx)
+            .map_err(|f| FastFieldNotAvailableError::new(ctx.reader.schema().get_field_entry(f)))?;
+        Ok(Self::Child::new(ff_reader, self.key, self.sub_agg.for_segment(&ctx.sub_agg_ctx())?))
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        for (key, bucket) in fruit.res {
+            let existing_bucket = harvest.res.entry(key)
+                .or_insert_with(|| self.sub_agg.create_fruit());
+
+            self.sub_agg.merge(existing_bucket, bucket);
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> TantivyResult<()> {
+        self.sub_agg.finalize_buckets(&mut harvest.res.values_mut().collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+pub struct TermsBySegmentAgg<FFReader, KeyFn, K, SubAgg>
+where
+    KeyFn: Fn(&FFReader, DocId) -> Option<K>,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: SegmentAgg,
+{
+    ff_reader: FFReader,
+    key: KeyFn,
+    sub_agg: SubAgg,
+}
+
+impl<FFReader, KeyFn, K, SubAgg> TermsBySegmentAgg<FFReader, KeyFn, K, SubAgg>
+where
+    KeyFn: Fn(&FFReader, DocId) -> Option<K>,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: SegmentAgg,
+{
+    fn new(ff_reader: FFReader, key: KeyFn, sub_agg: SubAgg) -> Self {
+        Self { ff_reader, key, sub_agg }
+    }
+}
+
+impl<FFReader, KeyFn, K, SubAgg> SegmentAgg for TermsBySegmentAgg<FFReader, KeyFn, K, SubAgg>
+where
+    KeyFn: Fn(&FFReader, DocId) -> Option<K>,
+    K: Eq + Hash + Ord + Send,
+    SubAgg: SegmentAgg,
+{
+    type Fruit = Terms<K, SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Terms::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        if let Some(key) = (self.key)(&self.ff_reader, doc) {
+            let sub_agg = &mut self.sub_agg;
+            let bucket = fruit.res.entry(key)
+                .or_insert_with(|| sub_agg.create_fruit());
+            sub_agg.collect(doc, score, bucket);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        for bucket in fruit.res.values_mut() {
+            self.sub_agg.finish(bucket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result as TantivyResult;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use super::terms_by_agg;
+
+    #[test]
+    fn test_terms_by_agg() -> TantivyResult<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = terms_by_agg(
+            |ctx| {
+                ctx.reader.fast_fields().f64(product_index.schema.price)
+                    .ok_or(product_index.schema.price)
+            },
+            |ff, doc| Some((ff.get(doc) / 10.0) as u64),
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let price_brackets = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            price_brackets.buckets(),
+            vec!(
+                (&0_u64, &(2_u64, Some(0.5_f64))),
+                (&1_u64, &(1_u64, Some(10_f64))),
+                (&5_u64, &(1_u64, Some(50_f64))),
+                (&10_u64, &(1_u64, Some(100.01_f64))),
+            )
+        );
+
+        let min_price = 1.0;
+        let agg = terms_by_agg(
+            |ctx| {
+                Ok((
+                    ctx.reader.fast_fields().u64(product_index.schema.category_id)
+                        .ok_or(product_index.schema.category_id)?,
+                    ctx.reader.fast_fields().f64(product_index.schema.price)
+                        .ok_or(product_index.schema.price)?,
+                ))
+            },
+            move |ff, doc| {
+                let price = ff.1.get(doc);
+                if price < min_price {
+                    return None;
+                }
+                Some((ff.0.get(doc), price >= 10.0))
+            },
+            count_agg()
+        );
+        let cat_expensive = searcher.agg_search(&AllQuery, &agg)?;
+        assert_eq!(
+            cat_expensive.buckets(),
+            vec!(
+                (&(1_u64, false), &1_u64),
+                (&(1_u64, true), &1_u64),
+                (&(2_u64, true), &2_u64),
+            )
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use tantivy::{
+    DocId, DocSet, InvertedIndexReader, Result, Score, Searcher, SegmentId, SegmentReader, TantivyError,
+};
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::termdict::TermOrdinal;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use super::terms::{IncludeExclude, Terms, TermsOptions, TermsOrder, TermsSet};
+
+/// Terms aggregation for indexed string and text fields.
+///
+/// Such fields have no fast field, so the term ordinals of every segment
+/// are uninverted from the postings lists. It is costly for fields
+/// with a lot of unique terms, the uninverted segments are cached by the aggregation
+/// so reuse it between searches.
+pub fn terms_agg_str<SubAgg>(field: Field, sub_agg: SubAgg) -> TermsAggStr<SubAgg>
+where
+    SubAgg: Agg,
+{
+    TermsAggStr {
+        field,
+        missing: false,
+        options: TermsOptions::default(),
+        include_exclude: IncludeExclude::default(),
+        term_ords: Arc::new(TermOrdsCache::default()),
+        sub_agg,
+    }
+}
+
+pub struct TermsAggStr<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    missing: bool,
+    options: TermsOptions<SubAgg::Fruit>,
+    include_exclude: IncludeExclude<String>,
+    term_ords: Arc<TermOrdsCache>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> TermsAggStr<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Collects documents without terms into a separate bucket
+    pub fn missing(mut self) -> Self {
+        self.missing = true;
+        self
+    }
+
+    /// Keeps only `size` first buckets according to the order,
+    /// by default all the buckets are returned
+    pub fn size(mut self, size: usize) -> Self {
+        self.options.size = Some(size);
+        self
+    }
+
+    /// Number of buckets kept for every segment, defaults to `size * 1.5 + 10`.
+    /// Less buckets means less memory but bigger error of the document counts.
+    pub fn shard_size(mut self, shard_size: usize) -> Self {
+        self.options.shard_size = Some(shard_size);
+        self
+    }
+
+    /// Order of the buckets, defaults to the document count descending
+    /// when the buckets are pruned and to the key ascending otherwise
+    pub fn order(mut self, order: TermsOrder<SubAgg::Fruit>) -> Self {
+        self.options.order = Some(order);
+        self
+    }
+
+    /// Removes buckets with less documents
+    pub fn min_doc_count(mut self, min_doc_count: u64) -> Self {
+        self.options.min_doc_count = min_doc_count;
+        self
+    }
+
+    /// Collects only terms from the set
+    pub fn include(mut self, terms: TermsSet<String>) -> Self {
+        self.include_exclude.include = Some(terms);
+        self
+    }
+
+    /// Skips terms from the set
+    pub fn exclude(mut self, terms: TermsSet<String>) -> Self {
+        self.include_exclude.exclude = Some(terms);
+        self
+    }
+}
+
+impl<SubAgg> Agg for TermsAggStr<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = TermsStr<SubAgg::Fruit>;
+    type Child = PreparedTermsAggStr<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        let field_entry = searcher.schema().get_field_entry(self.field);
+        if !field_entry.is_indexed() {
+            return Err(TantivyError::SchemaError(
+                format!("Field {} is not indexed", field_entry.name())
+            ));
+        }
+        self.include_exclude.validate()?;
+        let segment_ids = searcher.segment_readers().iter()
+            .map(|reader| reader.segment_id())
+            .collect::<HashSet<_>>();
+        self.term_ords.retain(&segment_ids);
+        Ok(Self::Child {
+            field: self.field,
+            missing: self.missing,
+            options: self.options.prepare(),
+            include_exclude: Arc::new(self.include_exclude.clone()),
+            term_ords: self.term_ords.clone(),
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedTermsAggStr<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    field: Field,
+    missing: bool,
+    options: TermsOptions<SubAgg::Fruit>,
+    include_exclude: Arc<IncludeExclude<String>>,
+    term_ords: Arc<TermOrdsCache>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedTermsAggStr<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = TermsStr<SubAgg::Fruit>;
+    type Child = TermsSegmentAggStr<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        TermsStr::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let inverted_index = ctx.reader.inverted_index(self.field);
+        let accepted_ords = if self.include_exclude.is_empty() {
+            None
+        } else {
+            Some(accepted_ords(&inverted_index, &self.include_exclude))
+        };
+        Ok(Self::Child {
+            term_ords: self.term_ords.get(ctx.reader, self.field),
+            inverted_index,
+            accepted_ords,
+            missing: self.missing,
+            counts_docs: self.options.counts_docs(),
+            options: self.options.clone(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        harvest.terms.merge(fruit.terms, &self.sub_agg);
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        let terms = &mut harvest.terms;
+        self.sub_agg.finalize_buckets(&mut terms.res.values_mut().chain(terms.missing.as_mut()).collect::<Vec<_>>())?;
+        self.options.finalize(terms);
+        Ok(())
+    }
+}
+
+pub struct TermsSegmentAggStr<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    term_ords: Arc<DocTermOrds>,
+    inverted_index: Arc<InvertedIndexReader>,
+    /// Included and not excluded term ordinals when there are include or exclude options
+    accepted_ords: Option<Vec<bool>>,
+    missing: bool,
+    options: TermsOptions<SubAgg::Fruit>,
+    counts_docs: bool,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for TermsSegmentAggStr<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = TermsStr<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        TermsStr::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, agg_value: &mut Self::Fruit) {
+        let ords = self.term_ords.ords(doc);
+        if ords.is_empty() && self.missing {
+            let bucket = agg_value.terms.missing
+                .get_or_insert_with(|| self.sub_agg.create_fruit());
+            self.sub_agg.collect(doc, score, bucket);
+            return;
+        }
+        for &ord in ords {
+            if self.accepted_ords.as_ref().map_or(false, |accepted| !accepted[ord as usize]) {
+                continue;
+            }
+            let sub_agg = &mut self.sub_agg;
+            let (doc_count, bucket) = agg_value.ord_res.entry(ord)
+                .or_insert_with(|| (0, sub_agg.create_fruit()));
+            *doc_count += 1;
+            sub_agg.collect(doc, score, bucket);
+        }
+    }
+
+    fn finish(&mut self, agg_value: &mut Self::Fruit) {
+        // Ordinals are unique inside of a segment and the fruit has no buckets of other segments
+        let terms = &mut agg_value.terms;
+        let mut buffer = vec!();
+        for (ord, (doc_count, bucket)) in agg_value.ord_res.drain() {
+            buffer.clear();
+            self.inverted_index.terms().ord_to_term(ord, &mut buffer);
+            let term = String::from_utf8_lossy(&buffer).into_owned();
+            if self.counts_docs {
+                terms.doc_counts.insert(term.clone(), doc_count);
+            }
+            terms.res.insert(term, bucket);
+        }
+        for bucket in terms.res.values_mut().chain(terms.missing.as_mut()) {
+            self.sub_agg.finish(bucket);
+        }
+        self.options.finish_segment(terms);
+    }
+}
+
+/// Buckets of the `terms_agg_str` keyed by the term text
+#[derive(Debug)]
+pub struct TermsStr<T> {
+    terms: Terms<String, T>,
+    /// Buckets and document counts of the collected segment keyed by term ordinals,
+    /// they are keyed by the terms when the segment is finished
+    ord_res: HashMap<TermOrdinal, (u64, T)>,
+}
+
+impl<T> TermsStr<T> {
+    fn new() -> Self {
+        Self {
+            terms: Terms::new(),
+            ord_res: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Deref for TermsStr<T> {
+    type Target = Terms<String, T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.terms
+    }
+}
+
+fn accepted_ords(inverted_index: &InvertedIndexReader, include_exclude: &IncludeExclude<String>) -> Vec<bool> {
+    let mut accepted = vec!(false; inverted_index.terms().num_terms());
+    let mut terms = inverted_index.terms().stream();
+    while terms.advance() {
+        let term = String::from_utf8_lossy(terms.key()).into_owned();
+        accepted[terms.term_ord() as usize] = include_exclude.accepts(&term);
+    }
+    accepted
+}
+
+/// Term ordinals of every document in a segment
+struct DocTermOrds {
+    offsets: Vec<usize>,
+    ords: Vec<TermOrdinal>,
+}
+
+impl DocTermOrds {
+    fn uninvert(inverted_index: &InvertedIndexReader, max_doc: DocId) -> Self {
+        let mut doc_ords = vec!();
+        let mut terms = inverted_index.terms().stream();
+        while terms.advance() {
+            let ord = terms.term_ord();
+            let mut postings = inverted_index.read_postings_from_terminfo(
+                terms.value(), IndexRecordOption::Basic
+            );
+            while postings.advance() {
+                doc_ords.push((postings.doc(), ord));
+            }
+        }
+        doc_ords.sort_unstable();
+
+        let mut offsets = Vec::with_capacity(max_doc as usize + 1);
+        let mut ords = Vec::with_capacity(doc_ords.len());
+        let mut doc_ords = doc_ords.into_iter().peekable();
+        for doc in 0..max_doc {
+            offsets.push(ords.len());
+            while let Some((_, ord)) = doc_ords.peek().filter(|(d, _)| *d == doc) {
+                ords.push(*ord);
+                doc_ords.next();
+            }
+        }
+        offsets.push(ords.len());
+
+        Self { offsets, ords }
+    }
+
+    fn ords(&self, doc: DocId) -> &[TermOrdinal] {
+        let doc = doc as usize;
+        &self.ords[self.offsets[doc]..self.offsets[doc + 1]]
+    }
+}
+
+/// Uninverted term ordinals of the segments
+#[derive(Default)]
+struct TermOrdsCache {
+    segments: Mutex<HashMap<SegmentId, Arc<DocTermOrds>>>,
+}
+
+impl TermOrdsCache {
+    fn get(&self, reader: &SegmentReader, field: Field) -> Arc<DocTermOrds> {
+        let segment_id = reader.segment_id();
+        if let Some(term_ords) = self.lock().get(&segment_id) {
+            return term_ords.clone();
+        }
+        // Other segments can be uninverted meanwhile
+        let term_ords = Arc::new(
+            DocTermOrds::uninvert(&reader.inverted_index(field), reader.max_doc())
+        );
+        self.lock().insert(segment_id, term_ords.clone());
+        term_ords
+    }
+
+    /// Drops segments that were merged or removed
+    fn retain(&self, segment_ids: &HashSet<SegmentId>) {
+        self.lock().retain(|segment_id, _| segment_ids.contains(segment_id));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SegmentId, Arc<DocTermOrds>>> {
+        self.segments.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::{AllQuery, RangeQuery};
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, TermsSet, count_agg, min_agg_f64};
+    use super::terms_agg_str;
+
+    #[test]
+    fn test_terms_agg_str() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let brand_agg = terms_agg_str(
+            product_index.schema.brand,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        let brands = searcher.agg_search(&AllQuery, &brand_agg)?;
+        assert_eq!(brands.get("acme"), Some(&(3_u64, Some(9.99_f64))));
+        assert_eq!(brands.get("globex"), Some(&(1_u64, Some(0.5_f64))));
+        assert_eq!(brands.get("initech"), Some(&(1_u64, Some(100.01_f64))));
+        assert_eq!(brands.get("unknown"), None);
+        assert_eq!(
+            brands.top_k(1, |b| b.0),
+            vec!(
+                (&"acme".to_string(), &(3_u64, Some(9.99_f64))),
+            )
+        );
+
+        // Uninverted segments are reused
+        let cheap_query = RangeQuery::new_f64(product_index.schema.price, 0_f64..10_f64);
+        let brands = searcher.agg_search(&cheap_query, &brand_agg)?;
+        assert_eq!(brands.get("acme"), Some(&(1_u64, Some(9.99_f64))));
+        assert_eq!(brands.get("globex"), Some(&(1_u64, Some(0.5_f64))));
+        assert_eq!(brands.get("initech"), None);
+
+        let brand_agg = terms_agg_str(product_index.schema.brand, count_agg())
+            .size(1);
+        let brands = searcher.agg_search(&AllQuery, &brand_agg)?;
+        assert_eq!(brands.buckets(), vec!((&"acme".to_string(), &3_u64)));
+        assert_eq!(brands.doc_count("acme"), Some(3_u64));
+        assert_eq!(brands.sum_other_doc_count(), 2);
+
+        let brand_agg = terms_agg_str(product_index.schema.brand, count_agg())
+            .include(TermsSet::Values(vec!("acme".to_string(), "globex".to_string()).into_iter().collect()))
+            .exclude(TermsSet::Range { from: Some("g".to_string()), to: None });
+        let brands = searcher.agg_search(&AllQuery, &brand_agg)?;
+        assert_eq!(brands.buckets(), vec!((&"acme".to_string(), &3_u64)));
+
+        let num_partitions = 2;
+        let mut partitioned_brands = vec!();
+        for partition in 0..num_partitions {
+            let brand_agg = terms_agg_str(product_index.schema.brand, count_agg())
+                .include(TermsSet::Partition { partition, num_partitions });
+            let brands = searcher.agg_search(&AllQuery, &brand_agg)?;
+            partitioned_brands.extend(
+                brands.buckets().into_iter().map(|(brand, &count)| (brand.clone(), count))
+            );
+        }
+        partitioned_brands.sort_unstable();
+        assert_eq!(
+            partitioned_brands,
+            vec!(("acme".to_string(), 3), ("globex".to_string(), 1), ("initech".to_string(), 1))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::mem;
+use std::sync::Arc;
+
+use tantivy::{DocId, Result, Score, Searcher};
+use tantivy::fastfield::{FastFieldNotAvailableError, FastFieldReader};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+
+/// Clusters documents into at most `buckets` buckets choosing bucket boundaries
+/// from the data.
+///
+/// Every segment keeps at most `shard_size` clusters merging the closest ones
+/// while collecting, after all segments were merged the closest neighbouring clusters
+/// are merged until there are `buckets` of them.
+pub fn variable_width_histogram_agg_f64<SubAgg>(
+    field: Field, buckets: usize, sub_agg: SubAgg
+) -> VariableWidthHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+{
+    VariableWidthHistogramAggF64 {
+        field,
+        num_buckets: buckets,
+        shard_size: None,
+        sub_agg,
+    }
+}
+
+pub struct VariableWidthHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    num_buckets: usize,
+    shard_size: Option<usize>,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> VariableWidthHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+{
+    /// Number of clusters kept for every segment, defaults to `buckets * 50`.
+    /// Less clusters means less memory but less precise bucket boundaries.
+    pub fn shard_size(mut self, shard_size: usize) -> Self {
+        self.shard_size = Some(shard_size);
+        self
+    }
+}
+
+impl<SubAgg> Agg for VariableWidthHistogramAggF64<SubAgg>
+where
+    SubAgg: Agg,
+    <SubAgg as Agg>::Child: Send,
+{
+    type Fruit = VariableWidthHistogram<SubAgg::Fruit>;
+    type Child = PreparedVariableWidthHistogramAggF64<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        let num_buckets = self.num_buckets.max(1);
+        Ok(Self::Child {
+            field: self.field,
+            num_buckets,
+            shard_size: self.shard_size.unwrap_or(num_buckets * 50).max(num_buckets),
+            sub_agg: Arc::new(self.sub_agg.prepare(searcher)?),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedVariableWidthHistogramAggF64<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    field: Field,
+    num_buckets: usize,
+    shard_size: usize,
+    /// Shared with the segments which merge sub aggregations of their clusters
+    sub_agg: Arc<SubAgg>,
+}
+
+impl<SubAgg> PreparedAgg for PreparedVariableWidthHistogramAggF64<SubAgg>
+where
+    SubAgg: PreparedAgg + Send,
+{
+    type Fruit = VariableWidthHistogram<SubAgg::Fruit>;
+    type Child = VariableWidthHistogramSegmentAggF64<SubAgg>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        VariableWidthHistogram::new()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let ff_reader = ctx.reader.fast_fields().f64(self.field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(self.field)
+                )
+            })?;
+        Ok(Self::Child {
+            ff_reader,
+            shard_size: self.shard_size,
+            prepared_sub_agg: self.sub_agg.clone(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, harvest: &mut Self::Fruit, fruit: Self::Fruit) {
+        harvest.buckets.extend(fruit.buckets);
+        if harvest.buckets.len() > 2 * self.shard_size {
+            let buckets = mem::take(&mut harvest.buckets);
+            harvest.buckets = reduce(buckets, self.shard_size, |left, right| {
+                self.sub_agg.merge(left, right)
+            });
+        }
+    }
+
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        let buckets = mem::take(&mut harvest.buckets);
+        let mut buckets = reduce(buckets, self.num_buckets, |left, right| {
+            self.sub_agg.merge(left, right)
+        });
+        self.sub_agg.finalize_buckets(&mut buckets.iter_mut().map(|bucket| &mut bucket.sub_agg).collect::<Vec<_>>())?;
+        harvest.buckets = buckets;
+        Ok(())
+    }
+}
+
+pub struct VariableWidthHistogramSegmentAggF64<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    ff_reader: FastFieldReader<f64>,
+    shard_size: usize,
+    prepared_sub_agg: Arc<SubAgg>,
+    sub_agg: SubAgg::Child,
+}
+
+impl<SubAgg> VariableWidthHistogramSegmentAggF64<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    /// Merges clusters of the segment until there are `shard_size` of them
+    fn compact(&mut self, fruit: &mut VariableWidthHistogram<SubAgg::Fruit>) {
+        let clusters = mem::take(&mut fruit.clusters).into_values().collect();
+        let sub_agg = &mut self.sub_agg;
+        let prepared_sub_agg = &*self.prepared_sub_agg;
+        let clusters = reduce(clusters, self.shard_size, |left, mut right| {
+            left.finish(sub_agg, prepared_sub_agg);
+            right.finish(sub_agg, prepared_sub_agg);
+            if let Some(right_finished) = right.finished {
+                match left.finished.as_mut() {
+                    Some(left_finished) => prepared_sub_agg.merge(left_finished, right_finished),
+                    None => left.finished = Some(right_finished),
+                }
+            }
+        });
+        fruit.clusters = clusters.into_iter()
+            .map(|cluster| (f64_to_ordered_key(cluster.min), cluster))
+            .collect();
+    }
+}
+
+impl<SubAgg> SegmentAgg for VariableWidthHistogramSegmentAggF64<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = VariableWidthHistogram<SubAgg::Fruit>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        VariableWidthHistogram::new()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        let value = self.ff_reader.get(doc);
+        if value.is_nan() {
+            return;
+        }
+        let key = f64_to_ordered_key(value);
+        // Clusters of a segment never overlap
+        let cluster_key = fruit.clusters.range(..=key).next_back()
+            .filter(|(_, cluster)| value <= cluster.max)
+            .map(|(&cluster_key, _)| cluster_key);
+        let cluster = match cluster_key {
+            Some(cluster_key) => fruit.clusters.get_mut(&cluster_key).unwrap(),
+            None => fruit.clusters.entry(key).or_insert(VariableWidthBucket {
+                key: value,
+                min: value,
+                max: value,
+                doc_count: 0,
+                sub_agg: ClusterFruits { finished: None, active: None },
+            }),
+        };
+        cluster.key += (value - cluster.key) / (cluster.doc_count + 1) as f64;
+        cluster.doc_count += 1;
+        let sub_agg = &mut self.sub_agg;
+        let active = cluster.sub_agg.active.get_or_insert_with(|| sub_agg.create_fruit());
+        sub_agg.collect(doc, score, active);
+
+        if fruit.clusters.len() > 2 * self.shard_size {
+            self.compact(fruit);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        if fruit.clusters.len() > self.shard_size {
+            self.compact(fruit);
+        }
+        for cluster in mem::take(&mut fruit.clusters).into_values() {
+            let VariableWidthBucket { key, min, max, doc_count, sub_agg: mut fruits } = cluster;
+            fruits.finish(&mut self.sub_agg, &*self.prepared_sub_agg);
+            fruit.buckets.push(VariableWidthBucket {
+                key,
+                min,
+                max,
+                doc_count,
+                sub_agg: fruits.finished.unwrap_or_else(|| self.prepared_sub_agg.create_fruit()),
+            });
+        }
+    }
+}
+
+/// Sub aggregations of a cluster of a segment.
+/// Sub aggregations are finished before clusters are merged,
+/// documents collected into the cluster after that go to a new active sub aggregation.
+#[derive(Debug)]
+struct ClusterFruits<T> {
+    finished: Option<T>,
+    active: Option<T>,
+}
+
+impl<T> ClusterFruits<T> {
+    fn finish<S, P>(&mut self, sub_agg: &mut S, prepared_sub_agg: &P)
+    where
+        S: SegmentAgg<Fruit = T>,
+        P: PreparedAgg<Fruit = T>,
+    {
+        if let Some(mut active) = self.active.take() {
+            sub_agg.finish(&mut active);
+            match self.finished.as_mut() {
+                Some(finished) => prepared_sub_agg.merge(finished, active),
+                None => self.finished = Some(active),
+            }
+        }
+    }
+}
+
+/// Maps floats to integers preserving their order
+fn f64_to_ordered_key(value: f64) -> u64 {
+    let bits = value.to_bits();
+    if value.is_sign_negative() {
+        !bits
+    } else {
+        bits | (1 << 63)
+    }
+}
+
+/// Merges the closest neighbouring buckets until there are at most `num_buckets` of them
+fn reduce<T, F>(
+    mut buckets: Vec<VariableWidthBucket<T>>, num_buckets: usize, merge_sub_aggs: F
+) -> Vec<VariableWidthBucket<T>>
+where
+    F: FnMut(&mut T, T),
+{
+    buckets.sort_by(|b1, b2| b1.key.total_cmp(&b2.key));
+    let mut clusters = Clusters::new(buckets);
+    clusters.reduce(num_buckets, merge_sub_aggs);
+    clusters.into_buckets()
+}
+
+/// Neighbouring clusters ordered by values, merging of two clusters
+/// only changes distances to their neighbours
+struct Clusters<T> {
+    buckets: Vec<Option<VariableWidthBucket<T>>>,
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    generations: Vec<u32>,
+}
+
+impl<T> Clusters<T> {
+    fn new(buckets: Vec<VariableWidthBucket<T>>) -> Self {
+        let len = buckets.len();
+        Self {
+            buckets: buckets.into_iter().map(Some).collect(),
+            prev: (0..len).map(|ix| ix.wrapping_sub(1)).collect(),
+            next: (1..=len).collect(),
+            generations: vec!(0; len),
+        }
+    }
+
+    fn distance(&self, left: usize, right: usize) -> u64 {
+        match (&self.buckets[left], &self.buckets[right]) {
+            // Distance is never negative so the order of bits is the same as of floats
+            (Some(l), Some(r)) => (r.key - l.key).to_bits(),
+            _ => u64::MAX,
+        }
+    }
+
+    /// Merges the closest clusters until there are at most `num_buckets` clusters
+    fn reduce<F>(&mut self, num_buckets: usize, mut merge_sub_aggs: F)
+    where
+        F: FnMut(&mut T, T),
+    {
+        let len = self.buckets.len();
+        let mut heap = BinaryHeap::with_capacity(len);
+        for left in 1..len {
+            heap.push(self.candidate(left - 1, left));
+        }
+
+        let mut num_clusters = len;
+        while num_clusters > num_buckets.max(1) {
+            let Reverse((_, left, left_gen, right, right_gen)) = match heap.pop() {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            if self.generations[left] != left_gen || self.generations[right] != right_gen
+                || self.buckets[left].is_none() || self.buckets[right].is_none()
+            {
+                continue;
+            }
+
+            let right_bucket = self.buckets[right].take().unwrap();
+            let left_bucket = self.buckets[left].as_mut().unwrap();
+            let doc_count = left_bucket.doc_count + right_bucket.doc_count;
+            left_bucket.key = (
+                left_bucket.key * left_bucket.doc_count as f64 +
+                    right_bucket.key * right_bucket.doc_count as f64
+            ) / doc_count as f64;
+            left_bucket.min = left_bucket.min.min(right_bucket.min);
+            left_bucket.max = left_bucket.max.max(right_bucket.max);
+            left_bucket.doc_count = doc_count;
+            merge_sub_aggs(&mut left_bucket.sub_agg, right_bucket.sub_agg);
+
+            self.generations[left] += 1;
+            let next = self.next[right];
+            self.next[left] = next;
+            if next < len {
+                self.prev[next] = left;
+                heap.push(self.candidate(left, next));
+            }
+            let prev = self.prev[left];
+            if prev < len {
+                heap.push(self.candidate(prev, left));
+            }
+            num_clusters -= 1;
+        }
+    }
+
+    fn candidate(&self, left: usize, right: usize) -> Reverse<(u64, usize, u32, usize, u32)> {
+        Reverse((
+            self.distance(left, right),
+            left, self.generations[left],
+            right, self.generations[right],
+        ))
+    }
+
+    fn into_buckets(self) -> Vec<VariableWidthBucket<T>> {
+        self.buckets.into_iter().flatten().collect()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VariableWidthBucket<T> {
+    /// Mean of the values in the bucket
+    pub key: f64,
+    pub min: f64,
+    pub max: f64,
+    pub doc_count: u64,
+    pub sub_agg: T,
+}
+
+#[derive(Debug)]
+pub struct VariableWidthHistogram<T> {
+    /// Clusters of a segment keyed by their minimum values
+    clusters: BTreeMap<u64, VariableWidthBucket<ClusterFruits<T>>>,
+    buckets: Vec<VariableWidthBucket<T>>,
+}
+
+impl<T> VariableWidthHistogram<T> {
+    fn new() -> Self {
+        Self {
+            clusters: BTreeMap::new(),
+            buckets: vec!(),
+        }
+    }
+
+    /// Buckets ordered by their keys
+    pub fn buckets(&self) -> &[VariableWidthBucket<T>] {
+        &self.buckets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, max_agg_u64};
+    use super::{VariableWidthBucket, variable_width_histogram_agg_f64};
+
+    fn assert_bucket<T>(bucket: &VariableWidthBucket<T>, key: f64, min: f64, max: f64, doc_count: u64, sub_agg: T)
+    where
+        T: PartialEq + std::fmt::Debug,
+    {
+        assert!((bucket.key - key).abs() < 1e-9, "{} != {}", bucket.key, key);
+        assert_eq!((bucket.min, bucket.max, bucket.doc_count), (min, max, doc_count));
+        assert_eq!(bucket.sub_agg, sub_agg);
+    }
+
+    #[test]
+    fn test_variable_width_histogram_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3_u16)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let price_hist = searcher.agg_search(
+            &AllQuery,
+            &variable_width_histogram_agg_f64(
+                product_index.schema.price,
+                3,
+                (count_agg(), max_agg_u64(product_index.schema.positive_opinion_percent))
+            )
+        )?;
+        let buckets = price_hist.buckets();
+        assert_eq!(buckets.len(), 3);
+        assert_bucket(&buckets[0], (0.5 + 9.99 + 10.0) / 3.0, 0.5, 10.0, 3, (3_u64, Some(100_u64)));
+        assert_bucket(&buckets[1], 50.0, 50.0, 50.0, 1, (1_u64, Some(85_u64)));
+        assert_bucket(&buckets[2], 100.01, 100.01, 100.01, 1, (1_u64, Some(99_u64)));
+
+        let price_hist = searcher.agg_search(
+            &AllQuery,
+            &variable_width_histogram_agg_f64(product_index.schema.price, 10, count_agg())
+        )?;
+        assert_eq!(price_hist.buckets().len(), 5);
+        assert_eq!(price_hist.buckets()[0].key, 0.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_width_histogram_agg_shard_size() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        // Clusters are merged while collecting
+        let price_hist = searcher.agg_search(
+            &AllQuery,
+            &variable_width_histogram_agg_f64(product_index.schema.price, 1, count_agg())
+                .shard_size(1)
+        )?;
+        let buckets = price_hist.buckets();
+        assert_eq!(buckets.len(), 1);
+        assert_bucket(&buckets[0], 170.5 / 5.0, 0.5, 100.01, 5, 5_u64);
+
+        Ok(())
+    }
+}
@@ -135,6 +135,14 @@ where
             _ => panic!("invalid state")
         }
     }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        match (&self.which, fruit) {
+            (Left(agg), Left(fruit)) => agg.finalize(fruit),
+            (Right(agg), Right(fruit)) => agg.finalize(fruit),
+            _ => panic!("invalid state")
+        }
+    }
 }
 
 pub struct EitherSegmentAgg<L, R>
@@ -168,6 +176,14 @@ where
             _ => panic!("invalid state"),
         }
     }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        match (&mut self.which, fruit) {
+            (Left(agg), Left(fruit)) => agg.finish(fruit),
+            (Right(agg), Right(fruit)) => agg.finish(fruit),
+            _ => panic!("invalid state"),
+        }
+    }
 }
 
 pub fn one_of_agg<L, R>(either: Either<L, R>) -> OneOfAgg<L, R>
@@ -247,6 +263,13 @@ where
             Right(a) => a.merge(acc, fruit),
         }
     }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        match &self.which {
+            Left(a) => a.finalize(fruit),
+            Right(a) => a.finalize(fruit),
+        }
+    }
 }
 
 pub struct OneOfSegmentAgg<L, R>
@@ -275,6 +298,13 @@ where
             Right(a) => a.collect(doc, score, fruit),
         }
     }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        match &mut self.which {
+            Left(a) => a.finish(fruit),
+            Right(a) => a.finish(fruit),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1,6 +1,6 @@
 use std::cmp::Ordering;
 
-use tantivy::{Result, DocId, Score, Searcher, SkipResult};
+use tantivy::{Result, DocId, Score, Searcher, SegmentReader, SkipResult};
 use tantivy::query::{Query, Scorer, Weight};
 
 use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
@@ -39,7 +39,7 @@ where
     }
 
     fn requires_scoring(&self) -> bool {
-        false
+        self.sub_agg.requires_scoring()
     }
 }
 
@@ -63,12 +63,9 @@ where
     }
 
     fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
-        let mut scorer = self.weight.scorer(ctx.reader)?;
-        let exhausted = !scorer.advance();
         Ok(Self::Child {
-            scorer,
-            exhausted,
-            sub_agg: self.sub_agg.for_segment(ctx)?,
+            matcher: FilterMatcher::new(self.weight.as_ref(), ctx.reader)?,
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
         })
     }
 
@@ -76,14 +73,17 @@ where
         self.sub_agg.merge(harvest, fruit);
     }
 
+    fn finalize(&self, harvest: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize(harvest)
+    }
+
 }
 
 pub struct FilterSegmentAgg<SubAgg>
 where
     SubAgg: SegmentAgg,
 {
-    scorer: Box<dyn Scorer>,
-    exhausted: bool,
+    matcher: FilterMatcher,
     sub_agg: SubAgg,
 }
 
@@ -98,23 +98,45 @@ where
     }
 
     fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        if self.matcher.matches(doc) {
+            self.sub_agg.collect(doc, score, fruit);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        self.sub_agg.finish(fruit);
+    }
+}
+
+/// Checks if documents match a filter query.
+/// Documents must be checked in the increasing order.
+pub(crate) struct FilterMatcher {
+    scorer: Box<dyn Scorer>,
+    exhausted: bool,
+}
+
+impl FilterMatcher {
+    pub(crate) fn new(weight: &dyn Weight, reader: &SegmentReader) -> Result<Self> {
+        let mut scorer = weight.scorer(reader)?;
+        let exhausted = !scorer.advance();
+        Ok(Self { scorer, exhausted })
+    }
+
+    pub(crate) fn matches(&mut self, doc: DocId) -> bool {
         if self.exhausted {
-            return;
+            return false;
         }
 
         match self.scorer.doc().cmp(&doc) {
-            Ordering::Equal => {
-                self.sub_agg.collect(doc, score, fruit);
-            }
-            Ordering::Greater => {}
+            Ordering::Equal => true,
+            Ordering::Greater => false,
             Ordering::Less => {
                 match self.scorer.skip_next(doc) {
-                    SkipResult::Reached => {
-                        self.sub_agg.collect(doc, score, fruit);
-                    }
-                    SkipResult::OverStep => {}
+                    SkipResult::Reached => true,
+                    SkipResult::OverStep => false,
                     SkipResult::End => {
                         self.exhausted = true;
+                        false
                     }
                 }
             }
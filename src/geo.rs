@@ -0,0 +1,237 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+use tantivy::{DocId, Result};
+use tantivy::fastfield::{FastFieldNotAvailableError, FastFieldReader};
+use tantivy::schema::Field;
+
+use crate::agg::AggSegmentContext;
+use crate::missing::PresenceReader;
+
+/// Mean radius of the Earth in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+pub const MAX_GEOHASH_PRECISION: usize = 12;
+
+/// Web mercator projection is defined only between these latitudes
+const MAX_MERCATOR_LAT: f64 = 85.051_128_779_806_59;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl GeoPoint {
+    pub fn new(lat: f64, lon: f64) -> Self {
+        Self { lat, lon }
+    }
+
+    /// Great-circle distance in meters calculated with the haversine formula
+    pub fn distance(&self, other: &GeoPoint) -> f64 {
+        let d_lat = (other.lat - self.lat).to_radians();
+        let d_lon = (other.lon - self.lon).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) +
+            self.lat.to_radians().cos() * other.lat.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistanceUnit {
+    Meters,
+    Kilometers,
+    Miles,
+}
+
+impl DistanceUnit {
+    pub fn to_meters(self, distance: f64) -> f64 {
+        match self {
+            DistanceUnit::Meters => distance,
+            DistanceUnit::Kilometers => distance * 1_000.0,
+            DistanceUnit::Miles => distance * 1_609.344,
+        }
+    }
+}
+
+/// Bounding box, boxes with the left longitude greater than the right one cross the dateline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeoBoundingBox {
+    pub top_left: GeoPoint,
+    pub bottom_right: GeoPoint,
+}
+
+impl GeoBoundingBox {
+    pub fn new(top_left: GeoPoint, bottom_right: GeoPoint) -> Self {
+        Self { top_left, bottom_right }
+    }
+
+    pub fn contains(&self, point: GeoPoint) -> bool {
+        if point.lat > self.top_left.lat || point.lat < self.bottom_right.lat {
+            return false;
+        }
+        if self.top_left.lon <= self.bottom_right.lon {
+            point.lon >= self.top_left.lon && point.lon <= self.bottom_right.lon
+        } else {
+            point.lon >= self.top_left.lon || point.lon <= self.bottom_right.lon
+        }
+    }
+}
+
+/// Replaces `geohash` with the geohash of the point of `precision` characters
+pub(crate) fn encode_geohash(point: GeoPoint, precision: usize, geohash: &mut String) {
+    geohash.clear();
+    let (mut lat_min, mut lat_max) = (-90.0, 90.0);
+    let (mut lon_min, mut lon_max) = (-180.0, 180.0);
+    let mut is_lon_bit = true;
+    for _ in 0..precision.min(MAX_GEOHASH_PRECISION) {
+        let mut ix = 0;
+        for _ in 0..5 {
+            let (value, min, max) = if is_lon_bit {
+                (point.lon, &mut lon_min, &mut lon_max)
+            } else {
+                (point.lat, &mut lat_min, &mut lat_max)
+            };
+            let mid = (*min + *max) / 2.0;
+            ix <<= 1;
+            if value >= mid {
+                ix |= 1;
+                *min = mid;
+            } else {
+                *max = mid;
+            }
+            is_lon_bit = !is_lon_bit;
+        }
+        geohash.push(GEOHASH_ALPHABET[ix] as char);
+    }
+}
+
+/// Web mercator map tile
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GeoTile {
+    pub zoom: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl GeoTile {
+    pub const MAX_ZOOM: u8 = 29;
+
+    pub fn new(zoom: u8, x: u32, y: u32) -> Self {
+        Self { zoom, x, y }
+    }
+
+    pub fn from_point(point: GeoPoint, zoom: u8) -> Self {
+        let zoom = zoom.min(Self::MAX_ZOOM);
+        let num_tiles = (1_u64 << zoom) as f64;
+        let max_ix = (1_u32 << zoom) - 1;
+        let lat = point.lat.clamp(-MAX_MERCATOR_LAT, MAX_MERCATOR_LAT).to_radians();
+        let x = ((point.lon + 180.0) / 360.0 * num_tiles).floor();
+        let y = ((1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * num_tiles).floor();
+        Self {
+            zoom,
+            x: (x.max(0.0) as u32).min(max_ix),
+            y: (y.max(0.0) as u32).min(max_ix),
+        }
+    }
+}
+
+impl fmt::Display for GeoTile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.zoom, self.x, self.y)
+    }
+}
+
+/// Reads points of documents from latitude and longitude fast fields.
+/// Documents without a location get the `(0, 0)` point.
+pub(crate) struct GeoPointReader {
+    lat_reader: FastFieldReader<f64>,
+    lon_reader: FastFieldReader<f64>,
+    presence: Option<PresenceReader>,
+}
+
+impl GeoPointReader {
+    /// Documents without a value in the multi-valued `presence_field` have no location
+    pub(crate) fn open(
+        ctx: &AggSegmentContext, lat_field: Field, lon_field: Field, presence_field: Option<Field>
+    ) -> Result<Self> {
+        Ok(Self {
+            lat_reader: f64_reader(ctx, lat_field)?,
+            lon_reader: f64_reader(ctx, lon_field)?,
+            presence: match presence_field {
+                Some(presence_field) => Some(PresenceReader::open(ctx, presence_field)?),
+                None => None,
+            },
+        })
+    }
+
+    pub(crate) fn get(&mut self, doc: DocId) -> Option<GeoPoint> {
+        if let Some(presence) = &mut self.presence {
+            if presence.is_missing(doc) {
+                return None;
+            }
+        }
+        Some(GeoPoint::new(self.lat_reader.get(doc), self.lon_reader.get(doc)))
+    }
+}
+
+fn f64_reader(ctx: &AggSegmentContext, field: Field) -> Result<FastFieldReader<f64>> {
+    Ok(
+        ctx.reader.fast_fields().f64(field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    ctx.reader.schema().get_field_entry(field)
+                )
+            })?
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DistanceUnit, GeoBoundingBox, GeoPoint, GeoTile, encode_geohash};
+
+    #[test]
+    fn test_distance() {
+        let berlin = GeoPoint::new(52.52, 13.405);
+        let hamburg = GeoPoint::new(53.5511, 9.9937);
+        let distance = berlin.distance(&hamburg);
+        assert!((distance - DistanceUnit::Kilometers.to_meters(255.25)).abs() < 10.0, "{}", distance);
+        assert_eq!(berlin.distance(&berlin), 0.0);
+
+        let distance = GeoPoint::new(0.0, 179.5).distance(&GeoPoint::new(0.0, -179.5));
+        assert!((distance - DistanceUnit::Miles.to_meters(69.09)).abs() < 10.0, "{}", distance);
+    }
+
+    #[test]
+    fn test_geohash() {
+        let mut geohash = String::new();
+        encode_geohash(GeoPoint::new(52.52, 13.405), 5, &mut geohash);
+        assert_eq!(geohash, "u33dc");
+        encode_geohash(GeoPoint::new(21.3069, -157.8583), 3, &mut geohash);
+        assert_eq!(geohash, "87z");
+        encode_geohash(GeoPoint::new(21.3069, -157.8583), 20, &mut geohash);
+        assert_eq!(geohash.len(), 12);
+    }
+
+    #[test]
+    fn test_geo_tile() {
+        let tile = GeoTile::from_point(GeoPoint::new(52.52, 13.405), 6);
+        assert_eq!(tile, GeoTile::new(6, 34, 20));
+        assert_eq!(tile.to_string(), "6/34/20");
+        assert_eq!(GeoTile::from_point(GeoPoint::new(90.0, 180.0), 1), GeoTile::new(1, 1, 0));
+        assert_eq!(GeoTile::from_point(GeoPoint::new(-90.0, -180.0), 0), GeoTile::new(0, 0, 0));
+    }
+
+    #[test]
+    fn test_bounding_box_contains() {
+        let europe = GeoBoundingBox::new(GeoPoint::new(60.0, -10.0), GeoPoint::new(40.0, 30.0));
+        assert!(europe.contains(GeoPoint::new(52.52, 13.405)));
+        assert!(!europe.contains(GeoPoint::new(35.6762, 139.6503)));
+
+        let pacific = GeoBoundingBox::new(GeoPoint::new(50.0, 130.0), GeoPoint::new(0.0, -150.0));
+        assert!(pacific.contains(GeoPoint::new(35.6762, 139.6503)));
+        assert!(pacific.contains(GeoPoint::new(21.3069, -157.8583)));
+        assert!(!pacific.contains(GeoPoint::new(52.52, 13.405)));
+    }
+}
@@ -0,0 +1,151 @@
+use tantivy::{DocId, Result, Score, Searcher, SegmentReader, TantivyError};
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+
+/// Aggregates all alive documents of the index regardless of the search query.
+///
+/// Must be a top-level aggregation, it can only be combined with others in a tuple or an either.
+pub fn global_agg<SubAgg>(sub_agg: SubAgg) -> GlobalAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    GlobalAgg { sub_agg }
+}
+
+pub struct GlobalAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> Agg for GlobalAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = SubAgg::Fruit;
+    type Child = PreparedGlobalAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+}
+
+pub struct PreparedGlobalAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedGlobalAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = SubAgg::Fruit;
+    type Child = GlobalSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        self.sub_agg.create_fruit()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        if !ctx.is_top_level() {
+            return Err(TantivyError::InvalidArgument(
+                "Global aggregation must be a top-level aggregation".to_string()
+            ));
+        }
+        Ok(Self::Child {
+            reader: ctx.reader.clone(),
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
+        self.sub_agg.merge(acc, fruit);
+    }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize(fruit)
+    }
+}
+
+pub struct GlobalSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    reader: SegmentReader,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for GlobalSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = SubAgg::Fruit;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        self.sub_agg.create_fruit()
+    }
+
+    fn collect(&mut self, _: DocId, _: Score, _: &mut Self::Fruit) {}
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        // Documents matched by the query are ignored,
+        // instead we walk through the whole segment
+        for doc in 0..self.reader.max_doc() {
+            if !self.reader.is_deleted(doc) {
+                self.sub_agg.collect(doc, 1.0, fruit);
+            }
+        }
+        self.sub_agg.finish(fruit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, terms_agg_u64};
+    use super::global_agg;
+
+    #[test]
+    fn test_global_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = (
+            count_agg(),
+            global_agg(
+                (
+                    count_agg(),
+                    terms_agg_u64(product_index.schema.category_id, count_agg())
+                )
+            ),
+        );
+        let (count, (global_count, global_cats)) = searcher.agg_search(
+            &product_index.category_query(1), &agg
+        )?;
+        assert_eq!(count, 2_u64);
+        assert_eq!(global_count, 5_u64);
+        assert_eq!(global_cats.get(&1_u64), Some(&2_u64));
+        assert_eq!(global_cats.get(&2_u64), Some(&3_u64));
+
+        let res = searcher.agg_search(
+            &product_index.category_query(1),
+            &terms_agg_u64(product_index.schema.category_id, global_agg(count_agg()))
+        );
+        assert!(res.is_err());
+
+        Ok(())
+    }
+}
@@ -2,7 +2,11 @@ pub mod agg;
 pub mod bucket;
 pub mod either;
 pub mod filter;
+pub mod geo;
+pub mod global;
 pub mod metric;
+pub mod missing;
+mod parent_docs;
 pub mod post_filter;
 pub mod searcher;
 pub mod tuple;
@@ -11,5 +15,8 @@ pub use searcher::AggSearcher;
 pub use bucket::*;
 pub use either::{Either, either_agg, one_of_agg};
 pub use filter::filter_agg;
+pub use geo::{DistanceUnit, GeoBoundingBox, GeoPoint, GeoTile};
+pub use global::global_agg;
 pub use metric::*;
+pub use missing::missing_agg;
 pub use post_filter::post_filter_agg;
@@ -0,0 +1,382 @@
+use tantivy::{DocId, Result, Score, Searcher};
+use tantivy::fastfield::{FastFieldNotAvailableError, FastFieldReader};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+use crate::geo::{GeoBoundingBox, GeoPoint, GeoPointReader};
+
+macro_rules! impl_geo_metric_agg {
+    ( $agg_fn:ident, $agg_struct:ident, $prepared_agg_struct:ident, $segment_agg_struct:ident, $fruit:ident ) => {
+
+pub struct $agg_struct {
+    lat_field: Field,
+    lon_field: Field,
+    presence: Option<Field>,
+    weight: Option<Field>,
+}
+
+pub fn $agg_fn(lat_field: Field, lon_field: Field) -> $agg_struct {
+    $agg_struct { lat_field, lon_field, presence: None, weight: None }
+}
+
+impl $agg_struct {
+    /// Skips documents without a value in the `presence_field`.
+    /// The presence field must be a multi-valued fast field.
+    pub fn presence(mut self, presence_field: Field) -> Self {
+        self.presence = Some(presence_field);
+        self
+    }
+}
+
+impl Agg for $agg_struct {
+    type Fruit = $fruit;
+    type Child = $prepared_agg_struct;
+
+    fn prepare(&self, _: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            lat_field: self.lat_field,
+            lon_field: self.lon_field,
+            presence: self.presence,
+            weight: self.weight,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+}
+
+pub struct $prepared_agg_struct {
+    lat_field: Field,
+    lon_field: Field,
+    presence: Option<Field>,
+    weight: Option<Field>,
+}
+
+impl PreparedAgg for $prepared_agg_struct {
+    type Fruit = $fruit;
+    type Child = $segment_agg_struct;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Default::default()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        let weights = match self.weight {
+            Some(weight_field) => Some(
+                ctx.reader.fast_fields().f64(weight_field)
+                    .ok_or_else(|| {
+                        FastFieldNotAvailableError::new(
+                            ctx.reader.schema().get_field_entry(weight_field)
+                        )
+                    })?
+            ),
+            None => None,
+        };
+        Ok(Self::Child {
+            points: GeoPointReader::open(ctx, self.lat_field, self.lon_field, self.presence)?,
+            weights,
+        })
+    }
+
+    fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
+        acc.merge(&fruit);
+    }
+}
+
+pub struct $segment_agg_struct {
+    points: GeoPointReader,
+    weights: Option<FastFieldReader<f64>>,
+}
+
+impl SegmentAgg for $segment_agg_struct {
+    type Fruit = $fruit;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        Default::default()
+    }
+
+    fn collect(&mut self, doc: DocId, _: Score, fruit: &mut Self::Fruit) {
+        let weight = match &self.weights {
+            Some(weights) => weights.get(doc),
+            None => 1.0,
+        };
+        if weight <= 0.0 {
+            return;
+        }
+        if let Some(point) = self.points.get(doc) {
+            fruit.add(point, weight);
+        }
+    }
+}
+
+    };
+}
+
+impl_geo_metric_agg!(geo_bounds_agg, GeoBoundsAgg, PreparedGeoBoundsAgg, GeoBoundsSegmentAgg, GeoBounds);
+impl_geo_metric_agg!(geo_centroid_agg, GeoCentroidAgg, PreparedGeoCentroidAgg, GeoCentroidSegmentAgg, GeoCentroid);
+
+impl GeoCentroidAgg {
+    /// Weights every point with the value of the `weight_field` of its document,
+    /// documents with a zero or negative weight are skipped.
+    /// The weight field must be a f64 fast field.
+    pub fn weight(mut self, weight_field: Field) -> Self {
+        self.weight = Some(weight_field);
+        self
+    }
+}
+
+/// Bounds of points with positive and negative longitudes are tracked separately,
+/// so the bounding box can cross the dateline when it is narrower that way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeoBounds {
+    top: f64,
+    bottom: f64,
+    pos_left: f64,
+    pos_right: f64,
+    neg_left: f64,
+    neg_right: f64,
+}
+
+impl Default for GeoBounds {
+    fn default() -> Self {
+        Self {
+            top: f64::NEG_INFINITY,
+            bottom: f64::INFINITY,
+            pos_left: f64::INFINITY,
+            pos_right: f64::NEG_INFINITY,
+            neg_left: f64::INFINITY,
+            neg_right: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl GeoBounds {
+    fn add(&mut self, point: GeoPoint, _weight: f64) {
+        self.top = self.top.max(point.lat);
+        self.bottom = self.bottom.min(point.lat);
+        if point.lon >= 0.0 {
+            self.pos_left = self.pos_left.min(point.lon);
+            self.pos_right = self.pos_right.max(point.lon);
+        } else {
+            self.neg_left = self.neg_left.min(point.lon);
+            self.neg_right = self.neg_right.max(point.lon);
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.top = self.top.max(other.top);
+        self.bottom = self.bottom.min(other.bottom);
+        self.pos_left = self.pos_left.min(other.pos_left);
+        self.pos_right = self.pos_right.max(other.pos_right);
+        self.neg_left = self.neg_left.min(other.neg_left);
+        self.neg_right = self.neg_right.max(other.neg_right);
+    }
+
+    /// Bounding box of the points, `None` when there are no points
+    pub fn bounding_box(&self) -> Option<GeoBoundingBox> {
+        if self.top == f64::NEG_INFINITY {
+            return None;
+        }
+        let (left, right) = if self.pos_left == f64::INFINITY {
+            (self.neg_left, self.neg_right)
+        } else if self.neg_right == f64::NEG_INFINITY {
+            (self.pos_left, self.pos_right)
+        } else {
+            let width = self.pos_right - self.neg_left;
+            let width_across_dateline = (180.0 - self.pos_left) + (self.neg_right + 180.0);
+            if width <= width_across_dateline {
+                (self.neg_left, self.pos_right)
+            } else {
+                (self.pos_left, self.neg_right)
+            }
+        };
+        Some(GeoBoundingBox::new(
+            GeoPoint::new(self.top, left),
+            GeoPoint::new(self.bottom, right),
+        ))
+    }
+}
+
+/// Points are summed as 3D vectors on the unit sphere,
+/// so the centroid is correct near the poles and the dateline.
+/// Every point counts as one unless the centroid is weighted with the `weight` option.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GeoCentroid {
+    x: f64,
+    y: f64,
+    z: f64,
+    count: u64,
+}
+
+impl GeoCentroid {
+    fn add(&mut self, point: GeoPoint, weight: f64) {
+        let (lat, lon) = (point.lat.to_radians(), point.lon.to_radians());
+        self.x += weight * lat.cos() * lon.cos();
+        self.y += weight * lat.cos() * lon.sin();
+        self.z += weight * lat.sin();
+        self.count += 1;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.x += other.x;
+        self.y += other.y;
+        self.z += other.z;
+        self.count += other.count;
+    }
+
+    /// Number of the points
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn centroid(&self) -> Option<GeoPoint> {
+        if self.count == 0 {
+            return None;
+        }
+        let lat = self.z.atan2(self.x.hypot(self.y));
+        let lon = self.y.atan2(self.x);
+        Some(GeoPoint::new(lat.to_degrees(), lon.to_degrees()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::{AllQuery, RangeQuery};
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, terms_agg_u64};
+    use crate::geo::{GeoBoundingBox, GeoPoint};
+    use super::{geo_bounds_agg, geo_centroid_agg};
+
+    fn assert_point_eq(point: GeoPoint, expected: GeoPoint) {
+        assert!(
+            (point.lat - expected.lat).abs() < 1e-6 && (point.lon - expected.lon).abs() < 1e-6,
+            "{:?} != {:?}", point, expected
+        );
+    }
+
+    #[test]
+    fn test_geo_bounds_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let cat_bounds = searcher.agg_search(
+            &AllQuery,
+            &terms_agg_u64(
+                product_index.schema.category_id,
+                geo_bounds_agg(product_index.schema.lat, product_index.schema.lon)
+            )
+        )?;
+        assert_eq!(
+            cat_bounds.get(&1).and_then(|b| b.bounding_box()),
+            Some(GeoBoundingBox::new(
+                GeoPoint::new(52.52, 13.0645),
+                GeoPoint::new(52.3906, 13.405),
+            ))
+        );
+        // Crossing the dateline is narrower than going from Honolulu to Tokyo through Europe
+        assert_eq!(
+            cat_bounds.get(&2).and_then(|b| b.bounding_box()),
+            Some(GeoBoundingBox::new(
+                GeoPoint::new(53.5511, 9.9937),
+                GeoPoint::new(21.3069, -157.8583),
+            ))
+        );
+
+        let bounds = searcher.agg_search(
+            &RangeQuery::new_f64(product_index.schema.price, 200_f64..300_f64),
+            &geo_bounds_agg(product_index.schema.lat, product_index.schema.lon)
+        )?;
+        assert_eq!(bounds.bounding_box(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geo_centroid_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = geo_centroid_agg(product_index.schema.lat, product_index.schema.lon);
+        let centroid = searcher.agg_search(&product_index.category_query(1), &agg)?;
+        assert_eq!(centroid.count(), 2);
+        assert_point_eq(centroid.centroid().unwrap(), GeoPoint::new(52.455422, 13.234500));
+
+        // Tokyo and Honolulu are on the different sides of the dateline
+        let centroid = searcher.agg_search(
+            &RangeQuery::new_f64(product_index.schema.price, 50_f64..200_f64),
+            &agg
+        )?;
+        assert_eq!(centroid.count(), 2);
+        assert_point_eq(centroid.centroid().unwrap(), GeoPoint::new(32.387073, 173.273034));
+
+        let centroid = searcher.agg_search(
+            &RangeQuery::new_f64(product_index.schema.price, 200_f64..300_f64),
+            &agg
+        )?;
+        assert_eq!(centroid.centroid(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geo_centroid_agg_weight() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = geo_centroid_agg(product_index.schema.lat, product_index.schema.lon)
+            .weight(product_index.schema.price);
+        let centroid = searcher.agg_search(&product_index.category_query(1), &agg)?;
+        assert_eq!(centroid.count(), 2);
+        assert_point_eq(centroid.centroid().unwrap(), GeoPoint::new(52.455390, 13.234415));
+
+        // Honolulu is twice as expensive as Tokyo, so the centroid moves past the dateline
+        let centroid = searcher.agg_search(
+            &RangeQuery::new_f64(product_index.schema.price, 50_f64..200_f64),
+            &agg
+        )?;
+        assert_eq!(centroid.count(), 2);
+        assert_point_eq(centroid.centroid().unwrap(), GeoPoint::new(29.119140, -175.698293));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geo_metric_aggs_presence() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram_single_segment(3)?;
+        product_index.index_test_products_with_offers()?;
+        let searcher = product_index.reader.searcher();
+
+        // Offers have no location, so they are at the null island without the presence field
+        let (lat, lon) = (product_index.schema.lat, product_index.schema.lon);
+        let bounds = searcher.agg_search(&AllQuery, &geo_bounds_agg(lat, lon))?;
+        assert_eq!(bounds.bounding_box().map(|b| b.bottom_right.lat), Some(0.0));
+        let bounds = searcher.agg_search(
+            &AllQuery,
+            &geo_bounds_agg(lat, lon).presence(product_index.schema.location_presence)
+        )?;
+        assert_eq!(
+            bounds.bounding_box(),
+            Some(GeoBoundingBox::new(
+                GeoPoint::new(53.5511, 9.9937),
+                GeoPoint::new(21.3069, -157.8583),
+            ))
+        );
+
+        let centroid = searcher.agg_search(&AllQuery, &geo_centroid_agg(lat, lon))?;
+        assert_eq!(centroid.count(), 12);
+        let centroid = searcher.agg_search(
+            &AllQuery,
+            &geo_centroid_agg(lat, lon).presence(product_index.schema.location_presence)
+        )?;
+        assert_eq!(centroid.count(), 5);
+
+        Ok(())
+    }
+}
@@ -1,9 +1,12 @@
 pub mod count;
+pub mod geo;
 pub mod minmax;
 pub mod percentile;
 pub mod sum;
+pub mod value;
 
 pub use count::count_agg;
+pub use geo::{geo_bounds_agg, geo_centroid_agg};
 pub use minmax::{
     max_agg_date, max_agg_dates,
     max_agg_f64, max_agg_f64s,
@@ -24,3 +27,4 @@ pub use sum::{
     sum_agg_i64, sum_agg_i64s,
     sum_agg_u64, sum_agg_u64s,
 };
+pub use value::MetricValue;
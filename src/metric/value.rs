@@ -0,0 +1,90 @@
+use super::percentile::Percentiles;
+
+/// Metric value of an aggregation fruit addressed by a path, see `TermsOrder::metric`.
+///
+/// Elements of a tuple are addressed by their index, nested paths are separated by dots:
+/// `"1"` is the second element of a tuple, `"2.0.5"` is the median of the percentiles
+/// in the third element. Plain metrics are addressed by an empty path.
+pub trait MetricValue {
+    fn metric_value(&self, path: &str) -> Option<f64>;
+}
+
+impl MetricValue for u64 {
+    fn metric_value(&self, path: &str) -> Option<f64> {
+        if path.is_empty() { Some(*self as f64) } else { None }
+    }
+}
+
+macro_rules! impl_metric_value_for_option {
+    ( $($type:ty),+ ) => { $(
+
+impl MetricValue for Option<$type> {
+    fn metric_value(&self, path: &str) -> Option<f64> {
+        if path.is_empty() { self.map(|value| value as f64) } else { None }
+    }
+}
+
+    )* };
+}
+
+impl_metric_value_for_option!(u64, i64, f64);
+
+/// The path is a quantile between 0 and 1
+impl MetricValue for Percentiles<f64> {
+    fn metric_value(&self, path: &str) -> Option<f64> {
+        let q = path.parse::<f64>().ok()?;
+        if (0.0..=1.0).contains(&q) { self.percentile(q) } else { None }
+    }
+}
+
+macro_rules! impl_metric_value_for_tuple {
+    ( $( $t:ident => $n:tt ),+ ) => {
+
+impl<$($t,)*> MetricValue for ($($t,)*)
+where $(
+    $t: MetricValue,
+)*
+{
+    fn metric_value(&self, path: &str) -> Option<f64> {
+        let (index, rest) = match path.find('.') {
+            Some(dot) => (&path[..dot], &path[dot + 1..]),
+            None => (path, ""),
+        };
+        match index.parse::<usize>().ok()? {
+            $(
+                $n => self.$n.metric_value(rest),
+            )*
+            _ => None,
+        }
+    }
+}
+
+    };
+}
+
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2, A4 => 3);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2, A4 => 3, A5 => 4);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2, A4 => 3, A5 => 4, A6 => 5);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2, A4 => 3, A5 => 4, A6 => 5, A7 => 6);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2, A4 => 3, A5 => 4, A6 => 5, A7 => 6, A8 => 7);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2, A4 => 3, A5 => 4, A6 => 5, A7 => 6, A8 => 7, A9 => 8);
+impl_metric_value_for_tuple!(A1 => 0, A2 => 1, A3 => 2, A4 => 3, A5 => 4, A6 => 5, A7 => 6, A8 => 7, A9 => 8, A10 => 9);
+
+#[cfg(test)]
+mod tests {
+    use super::MetricValue;
+
+    #[test]
+    fn test_metric_value() {
+        let fruit = (3_u64, (Some(0.5_f64), None::<i64>));
+        assert_eq!(fruit.metric_value("0"), Some(3.0));
+        assert_eq!(fruit.metric_value("1.0"), Some(0.5));
+        assert_eq!(fruit.metric_value("1.1"), None);
+        assert_eq!(fruit.metric_value("1"), None);
+        assert_eq!(fruit.metric_value("2"), None);
+        assert_eq!(fruit.metric_value("0.1"), None);
+        assert_eq!(3_u64.metric_value(""), Some(3.0));
+    }
+}
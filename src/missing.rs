@@ -0,0 +1,167 @@
+use tantivy::{DocId, Result, Score, Searcher, SegmentReader};
+use tantivy::fastfield::{FastFieldNotAvailableError, MultiValueIntFastFieldReader};
+use tantivy::schema::Field;
+
+use crate::agg::{Agg, AggSegmentContext, PreparedAgg, SegmentAgg};
+
+/// Aggregates documents that have no values in the multi-valued fast field.
+///
+/// Single-valued fast fields return a default value for documents without a value
+/// so it is impossible to detect absence using them. Index an additional multi-valued
+/// presence field for such a case and pass it instead.
+pub fn missing_agg<SubAgg>(field: Field, sub_agg: SubAgg) -> MissingAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    MissingAgg { field, sub_agg }
+}
+
+pub struct MissingAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    field: Field,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> Agg for MissingAgg<SubAgg>
+where
+    SubAgg: Agg,
+{
+    type Fruit = SubAgg::Fruit;
+    type Child = PreparedMissingAgg<SubAgg::Child>;
+
+    fn prepare(&self, searcher: &Searcher) -> Result<Self::Child> {
+        Ok(Self::Child {
+            field: self.field,
+            sub_agg: self.sub_agg.prepare(searcher)?,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sub_agg.requires_scoring()
+    }
+}
+
+pub struct PreparedMissingAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    field: Field,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> PreparedAgg for PreparedMissingAgg<SubAgg>
+where
+    SubAgg: PreparedAgg,
+{
+    type Fruit = SubAgg::Fruit;
+    type Child = MissingSegmentAgg<SubAgg::Child>;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        self.sub_agg.create_fruit()
+    }
+
+    fn for_segment(&self, ctx: &AggSegmentContext) -> Result<Self::Child> {
+        Ok(Self::Child {
+            presence: PresenceReader::open(ctx, self.field)?,
+            sub_agg: self.sub_agg.for_segment(&ctx.sub_agg_ctx())?,
+        })
+    }
+
+    fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
+        self.sub_agg.merge(acc, fruit);
+    }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        self.sub_agg.finalize(fruit)
+    }
+}
+
+pub struct MissingSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    presence: PresenceReader,
+    sub_agg: SubAgg,
+}
+
+impl<SubAgg> SegmentAgg for MissingSegmentAgg<SubAgg>
+where
+    SubAgg: SegmentAgg,
+{
+    type Fruit = SubAgg::Fruit;
+
+    fn create_fruit(&self) -> Self::Fruit {
+        self.sub_agg.create_fruit()
+    }
+
+    fn collect(&mut self, doc: DocId, score: Score, fruit: &mut Self::Fruit) {
+        if self.presence.is_missing(doc) {
+            self.sub_agg.collect(doc, score, fruit);
+        }
+    }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        self.sub_agg.finish(fruit);
+    }
+}
+
+/// Detects documents without values using the index of a multi-valued fast field
+pub(crate) struct PresenceReader {
+    ff_reader: MultiValueIntFastFieldReader<u64>,
+    vals: Vec<u64>,
+}
+
+impl PresenceReader {
+    pub(crate) fn open(ctx: &AggSegmentContext, field: Field) -> Result<Self> {
+        Self::for_reader(ctx.reader, field)
+    }
+
+    pub(crate) fn for_reader(reader: &SegmentReader, field: Field) -> Result<Self> {
+        let ff_reader = reader.fast_fields().u64s_lenient(field)
+            .ok_or_else(|| {
+                FastFieldNotAvailableError::new(
+                    reader.schema().get_field_entry(field)
+                )
+            })?;
+        Ok(Self {
+            ff_reader,
+            vals: vec!(),
+        })
+    }
+
+    pub(crate) fn is_missing(&mut self, doc: DocId) -> bool {
+        self.ff_reader.get_vals(doc, &mut self.vals);
+        self.vals.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::Result;
+    use tantivy::query::AllQuery;
+
+    use test_fixtures::ProductIndex;
+
+    use crate::{AggSearcher, count_agg, min_agg_f64};
+    use super::missing_agg;
+
+    #[test]
+    fn test_missing_agg() -> Result<()> {
+        let mut product_index = ProductIndex::create_in_ram(3)?;
+        product_index.index_test_products()?;
+        let searcher = product_index.reader.searcher();
+
+        let agg = missing_agg(
+            product_index.schema.attr_facets,
+            (count_agg(), min_agg_f64(product_index.schema.price))
+        );
+        assert_eq!(
+            searcher.agg_search(&AllQuery, &agg)?,
+            (3_u64, Some(0.5_f64))
+        );
+
+        Ok(())
+    }
+}
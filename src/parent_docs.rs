@@ -0,0 +1,86 @@
+use tantivy::DocId;
+
+/// Bitset of the parent documents of a segment.
+///
+/// Child documents of a parent are indexed right after it, before the next parent.
+pub(crate) struct ParentDocs {
+    words: Vec<u64>,
+    max_doc: DocId,
+}
+
+impl ParentDocs {
+    pub(crate) fn new(max_doc: DocId) -> Self {
+        Self {
+            words: vec!(0; (max_doc as usize + 63) / 64),
+            max_doc,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, doc: DocId) {
+        self.words[doc as usize / 64] |= 1 << (doc % 64);
+    }
+
+    pub(crate) fn contains(&self, doc: DocId) -> bool {
+        self.words[doc as usize / 64] & (1 << (doc % 64)) != 0
+    }
+
+    /// First parent document starting from `doc`
+    pub(crate) fn next_parent(&self, doc: DocId) -> Option<DocId> {
+        if doc >= self.max_doc {
+            return None;
+        }
+        let mut word_ix = doc as usize / 64;
+        let mut word = self.words[word_ix] & (u64::MAX << (doc % 64));
+        loop {
+            if word != 0 {
+                return Some(word_ix as DocId * 64 + word.trailing_zeros());
+            }
+            word_ix += 1;
+            word = *self.words.get(word_ix)?;
+        }
+    }
+
+    /// Last parent document up to `doc` inclusive
+    pub(crate) fn prev_parent(&self, doc: DocId) -> Option<DocId> {
+        let mut word_ix = doc as usize / 64;
+        let mut word = self.words[word_ix] & (u64::MAX >> (63 - doc % 64));
+        loop {
+            if word != 0 {
+                return Some(word_ix as DocId * 64 + 63 - word.leading_zeros());
+            }
+            if word_ix == 0 {
+                return None;
+            }
+            word_ix -= 1;
+            word = self.words[word_ix];
+        }
+    }
+
+    /// Range of the child documents of the `parent`
+    pub(crate) fn children(&self, parent: DocId) -> std::ops::Range<DocId> {
+        let end = self.next_parent(parent + 1).unwrap_or(self.max_doc);
+        (parent + 1)..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParentDocs;
+
+    #[test]
+    fn test_parent_docs() {
+        let mut parent_docs = ParentDocs::new(200);
+        for &doc in &[3, 64, 130] {
+            parent_docs.insert(doc);
+        }
+        assert!(parent_docs.contains(64));
+        assert!(!parent_docs.contains(65));
+        assert_eq!(parent_docs.children(3), 4..64);
+        assert_eq!(parent_docs.children(64), 65..130);
+        assert_eq!(parent_docs.children(130), 131..200);
+        assert_eq!(parent_docs.prev_parent(2), None);
+        assert_eq!(parent_docs.prev_parent(3), Some(3));
+        assert_eq!(parent_docs.prev_parent(129), Some(64));
+        assert_eq!(parent_docs.prev_parent(199), Some(130));
+    }
+}
@@ -81,12 +81,16 @@ where
     fn for_segment(&self, ctx: &AggSegmentContext) -> TantivyResult<Self::Child> {
         let ff_reader = (self.ff_reader_fetcher)(ctx)
             .map_err(|f| FastFieldNotAvailableError::new(ctx.reader.schema().get_field_entry(f)))?;
-        Ok(Self::Child::new(self.filter, ff_reader, self.sub_agg.for_segment(ctx)?))
+        Ok(Self::Child::new(self.filter, ff_reader, self.sub_agg.for_segment(&ctx.sub_agg_ctx())?))
     }
 
     fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
         self.sub_agg.merge(acc, fruit);
     }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> TantivyResult<()> {
+        self.sub_agg.finalize(fruit)
+    }
 }
 
 pub struct PostFilterSegmentAgg<FFReader, Filter, SubAgg>
@@ -125,6 +129,10 @@ where
             self.sub_agg.collect(doc, score, fruit);
         }
     }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        self.sub_agg.finish(fruit);
+    }
 }
 
 macro_rules! impl_post_filter_agg_for_type {
@@ -198,12 +206,16 @@ where
             .ok_or(
                 FastFieldNotAvailableError::new(ctx.reader.schema().get_field_entry(self.field))
             )?;
-        Ok(Self::Child::new(ff_reader, self.filter, self.sub_agg.for_segment(ctx)?))
+        Ok(Self::Child::new(ff_reader, self.filter, self.sub_agg.for_segment(&ctx.sub_agg_ctx())?))
     }
 
     fn merge(&self, acc: &mut Self::Fruit, fruit: Self::Fruit) {
         self.sub_agg.merge(acc, fruit);
     }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> TantivyResult<()> {
+        self.sub_agg.finalize(fruit)
+    }
 }
 
     };
@@ -247,6 +259,10 @@ where
             self.sub_agg.collect(doc, score, fruit);
         }
     }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        self.sub_agg.finish(fruit);
+    }
 }
 
     )* };
@@ -295,6 +311,10 @@ where
             }
         }
     }
+
+    fn finish(&mut self, fruit: &mut Self::Fruit) {
+        self.sub_agg.finish(fruit);
+    }
 }
 
     )* };
@@ -29,25 +29,22 @@ fn collect_segment<A: PreparedAgg>(
     weight: &dyn Weight,
     segment_ord: u32,
     segment_reader: &SegmentReader,
-    harvest: &mut A::Fruit,
-) -> Result<()> {
+) -> Result<A::Fruit> {
+    let mut harvest = agg.create_fruit();
     let mut scorer = weight.scorer(segment_reader)?;
-    let agg_ctx = AggSegmentContext {
-        segment_ord,
-        reader: segment_reader,
-        scorer: scorer.as_ref(),
-    };
+    let agg_ctx = AggSegmentContext::new(segment_ord, segment_reader, scorer.as_ref());
     let mut segment_agg = agg.for_segment(&agg_ctx)?;
     if let Some(delete_bitset) = segment_reader.delete_bitset() {
         scorer.for_each(&mut |doc, score| {
             if delete_bitset.is_alive(doc) {
-                segment_agg.collect(doc, score, harvest);
+                segment_agg.collect(doc, score, &mut harvest);
             }
         });
     } else {
-        scorer.for_each(&mut |doc, score| segment_agg.collect(doc, score, harvest));
+        scorer.for_each(&mut |doc, score| segment_agg.collect(doc, score, &mut harvest));
     }
-    Ok(())
+    segment_agg.finish(&mut harvest);
+    Ok(harvest)
 }
 
 impl AggSearcher for Searcher {
@@ -62,31 +59,31 @@ impl AggSearcher for Searcher {
         let weight = query.weight(self.deref(), scoring_enabled)?;
         let prepared_agg = agg.prepare(self.deref())?;
         let segment_readers = self.segment_readers();
-        let harvest = match executor {
+        let mut harvest = match executor {
             Executor::SingleThread => {
+                // Every segment gets its own fruit so segment aggregations
+                // never see buckets collected from other segments
                 let mut harvest = prepared_agg.create_fruit();
                 for (segment_ord, segment_reader) in segment_readers.iter().enumerate() {
-                    collect_segment(
+                    let fruit = collect_segment(
                         &prepared_agg,
                         weight.as_ref(),
                         segment_ord as u32,
                         segment_reader,
-                        &mut harvest,
                     )?;
+                    prepared_agg.merge(&mut harvest, fruit);
                 }
                 harvest
             }
             executor @ Executor::ThreadPool(_) => {
                 let fruits = executor.map(
                     |(segment_ord, segment_reader)| {
-                        let mut fruit = prepared_agg.create_fruit();
                         collect_segment(
                             &prepared_agg,
                             weight.as_ref(),
                             segment_ord as u32,
                             segment_reader,
-                            &mut fruit,
-                        ).map(|_| fruit)
+                        )
                     },
                     segment_readers.iter().enumerate(),
                 )?;
@@ -97,6 +94,7 @@ impl AggSearcher for Searcher {
                 harvest
             }
         };
+        prepared_agg.finalize(&mut harvest)?;
         Ok(harvest)
     }
 }
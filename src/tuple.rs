@@ -47,6 +47,22 @@ where $(
             self.$n.merge(&mut acc.$n, fruit.$n);
         )*
     }
+
+    fn finalize(&self, fruit: &mut Self::Fruit) -> Result<()> {
+        $(
+            self.$n.finalize(&mut fruit.$n)?;
+        )*
+        Ok(())
+    }
+
+    fn finalize_buckets(&self, fruits: &mut [&mut Self::Fruit]) -> Result<()> {
+        $(
+            self.$n.finalize_buckets(
+                &mut fruits.iter_mut().map(|fruit| &mut fruit.$n).collect::<Vec<_>>()
+            )?;
+        )*
+        Ok(())
+    }
 }
 
 impl<$($a,)*> SegmentAgg for ($($a,)*)
@@ -65,6 +81,12 @@ where $(
             self.$n.collect(doc, score, &mut output.$n);
         )*
     }
+
+    fn finish(&mut self, output: &mut Self::Fruit) {
+        $(
+            self.$n.finish(&mut output.$n);
+        )*
+    }
 }
 
     };
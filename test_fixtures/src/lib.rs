@@ -1,7 +1,7 @@
-use tantivy::{doc, Index, IndexReader, IndexWriter, Result, Term};
+use tantivy::{doc, Document, Index, IndexReader, IndexWriter, Result, Term};
 use tantivy::chrono::{DateTime, Utc};
 use tantivy::directory::RAMDirectory;
-use tantivy::schema::{Field, Schema, FAST, INDEXED, STORED, IntOptions, Cardinality, IndexRecordOption};
+use tantivy::schema::{Facet, Field, Schema, FAST, INDEXED, STORED, STRING, IntOptions, Cardinality, IndexRecordOption};
 use tantivy::query::TermQuery;
 
 pub struct ProductIndex {
@@ -13,10 +13,23 @@ pub struct ProductIndex {
 
 impl ProductIndex {
     pub fn create_in_ram(heap_size_in_megabytes: u16) -> Result<Self> {
+        Self::create(heap_size_in_megabytes, None)
+    }
+
+    /// Uses a single indexing thread, so all the test products get into one segment
+    pub fn create_in_ram_single_segment(heap_size_in_megabytes: u16) -> Result<Self> {
+        Self::create(heap_size_in_megabytes, Some(1))
+    }
+
+    fn create(heap_size_in_megabytes: u16, num_threads: Option<usize>) -> Result<Self> {
         let dir = RAMDirectory::create();
         let schema = ProductSchema::create();
         let index = Index::create(dir, schema.schema.clone())?;
-        let index_writer = index.writer(heap_size_in_megabytes as usize * 1_000_000)?;
+        let heap_size = heap_size_in_megabytes as usize * 1_000_000;
+        let index_writer = match num_threads {
+            Some(num_threads) => index.writer_with_num_threads(num_threads, heap_size)?,
+            None => index.writer(heap_size)?,
+        };
         let index_reader = index.reader()?;
 
         Ok(Self {
@@ -28,51 +41,143 @@ impl ProductIndex {
     }
 
     pub fn index_test_products(&mut self) -> Result<u64> {
-        self.writer.add_document(doc!(
-            self.schema.id => 1_u64,
-            self.schema.category_id => 1_u64,
-            self.schema.tag_ids => 111_u64,
-            self.schema.tag_ids => 112_u64,
-            self.schema.tag_ids => 211_u64,
-            self.schema.price => 9.99_f64,
-            self.schema.positive_opinion_percent => 82_u64,
-            self.schema.date_created => DateTime::parse_from_rfc3339("2019-12-31T23:59:59+00:00").unwrap().with_timezone(&Utc),
-        ));
-        self.writer.add_document(doc!(
-            self.schema.id => 2_u64,
-            self.schema.category_id => 1_u64,
-            self.schema.tag_ids => 111_u64,
-            self.schema.tag_ids => 211_u64,
-            self.schema.tag_ids => 320_u64,
-            self.schema.price => 10_f64,
-            self.schema.positive_opinion_percent => 100_u64,
-            self.schema.date_created => DateTime::parse_from_rfc3339("2020-01-01T00:00:00+00:00").unwrap().with_timezone(&Utc),
-        ));
-        self.writer.add_document(doc!(
-            self.schema.id => 3_u64,
-            self.schema.category_id => 2_u64,
-            self.schema.tag_ids => 211_u64,
-            self.schema.tag_ids => 311_u64,
-            self.schema.price => 0.5_f64,
-            self.schema.positive_opinion_percent => 71_u64,
-        ));
-        self.writer.add_document(doc!(
-            self.schema.id => 4_u64,
-            self.schema.category_id => 2_u64,
-            self.schema.tag_ids => 320_u64,
-            self.schema.price => 50_f64,
-            self.schema.positive_opinion_percent => 85_u64,
-            self.schema.date_created => DateTime::parse_from_rfc3339("2019-12-31T23:59:59+01:00").unwrap().with_timezone(&Utc),
-        ));
-        self.writer.add_document(doc!(
-            self.schema.id => 5_u64,
-            self.schema.category_id => 2_u64,
-            self.schema.tag_ids => 311_u64,
-            self.schema.tag_ids => 511_u64,
-            self.schema.price => 100.01_f64,
-            self.schema.positive_opinion_percent => 99_u64,
-            self.schema.date_created => DateTime::parse_from_rfc3339("2019-12-31T23:59:59-01:00").unwrap().with_timezone(&Utc),
-        ));
+        for product in self.test_products() {
+            self.writer.add_document(product);
+        }
+        self.commit()
+    }
+
+    /// Every product is followed by the documents of its offers linked by the `product_id` field.
+    /// Use a single segment index so products and offers are not mixed up by indexing threads.
+    pub fn index_test_products_with_offers(&mut self) -> Result<u64> {
+        let offers = vec!(
+            vec!((1_u64, 9.49_f64), (1, 9.79), (2, 9.99)),
+            vec!((1, 11.0)),
+            vec!(),
+            vec!((2, 45.0), (3, 52.0)),
+            vec!((3, 99.0)),
+        );
+        for (product_ix, (product, product_offers)) in self.test_products().into_iter().zip(offers).enumerate() {
+            self.writer.add_document(product);
+            for (shop_id, price) in product_offers {
+                self.writer.add_document(self.offer(product_ix as u64 + 1, shop_id, price));
+            }
+        }
+        self.commit()
+    }
+
+    /// Commits more offers of a product, they get into a separate segment
+    pub fn index_offers(&mut self, product_id: u64, offers: Vec<(u64, f64)>) -> Result<u64> {
+        for (shop_id, price) in offers {
+            self.writer.add_document(self.offer(product_id, shop_id, price));
+        }
+        self.commit()
+    }
+
+    fn offer(&self, product_id: u64, shop_id: u64, price: f64) -> Document {
+        let shop_name = match shop_id {
+            1 => "bestbuy",
+            2 => "walmart",
+            _ => "target",
+        };
+        doc!(
+            self.schema.product_id => product_id,
+            self.schema.product_id_presence => 1_u64,
+            self.schema.shop_id => shop_id,
+            self.schema.shop_name => shop_name,
+            self.schema.price => price,
+        )
+    }
+
+    fn test_products(&self) -> Vec<Document> {
+        vec!(
+            doc!(
+                self.schema.id => 1_u64,
+                self.schema.id_presence => 1_u64,
+                self.schema.brand => "acme",
+                self.schema.category_id => 1_u64,
+                self.schema.category_path => Facet::from("/electronics/phones/smartphones"),
+                self.schema.tag_ids => 111_u64,
+                self.schema.tag_ids => 112_u64,
+                self.schema.tag_ids => 211_u64,
+                self.schema.attr_facets => (1_u64 << 32) | 1_u64,
+                self.schema.attr_facets => (2_u64 << 32) | 3_u64,
+                self.schema.price => 9.99_f64,
+                self.schema.lat => 52.52_f64,
+                self.schema.lon => 13.405_f64,
+                self.schema.location_presence => 1_u64,
+                self.schema.positive_opinion_percent => 82_u64,
+                self.schema.date_created => DateTime::parse_from_rfc3339("2019-12-31T23:59:59+00:00").unwrap().with_timezone(&Utc),
+                self.schema.date_created_presence => 1_u64,
+            ),
+            doc!(
+                self.schema.id => 2_u64,
+                self.schema.id_presence => 1_u64,
+                self.schema.brand => "acme",
+                self.schema.category_id => 1_u64,
+                self.schema.category_path => Facet::from("/electronics/phones/accessories"),
+                self.schema.category_path => Facet::from("/electronics/audio"),
+                self.schema.tag_ids => 111_u64,
+                self.schema.tag_ids => 211_u64,
+                self.schema.tag_ids => 320_u64,
+                self.schema.attr_facets => (1_u64 << 32) | 2_u64,
+                self.schema.attr_facets => (2_u64 << 32) | 3_u64,
+                self.schema.price => 10_f64,
+                self.schema.lat => 52.3906_f64,
+                self.schema.lon => 13.0645_f64,
+                self.schema.location_presence => 1_u64,
+                self.schema.positive_opinion_percent => 100_u64,
+                self.schema.date_created => DateTime::parse_from_rfc3339("2020-01-01T00:00:00+00:00").unwrap().with_timezone(&Utc),
+                self.schema.date_created_presence => 1_u64,
+            ),
+            doc!(
+                self.schema.id => 3_u64,
+                self.schema.id_presence => 1_u64,
+                self.schema.brand => "globex",
+                self.schema.category_id => 2_u64,
+                self.schema.category_path => Facet::from("/electronics/audio/headphones"),
+                self.schema.tag_ids => 211_u64,
+                self.schema.tag_ids => 311_u64,
+                self.schema.price => 0.5_f64,
+                self.schema.lat => 53.5511_f64,
+                self.schema.lon => 9.9937_f64,
+                self.schema.location_presence => 1_u64,
+                self.schema.positive_opinion_percent => 71_u64,
+            ),
+            doc!(
+                self.schema.id => 4_u64,
+                self.schema.id_presence => 1_u64,
+                self.schema.brand => "acme",
+                self.schema.category_id => 2_u64,
+                self.schema.category_path => Facet::from("/home/kitchen"),
+                self.schema.tag_ids => 320_u64,
+                self.schema.price => 50_f64,
+                self.schema.lat => 35.6762_f64,
+                self.schema.lon => 139.6503_f64,
+                self.schema.location_presence => 1_u64,
+                self.schema.positive_opinion_percent => 85_u64,
+                self.schema.date_created => DateTime::parse_from_rfc3339("2019-12-31T23:59:59+01:00").unwrap().with_timezone(&Utc),
+                self.schema.date_created_presence => 1_u64,
+            ),
+            doc!(
+                self.schema.id => 5_u64,
+                self.schema.id_presence => 1_u64,
+                self.schema.brand => "initech",
+                self.schema.category_id => 2_u64,
+                self.schema.tag_ids => 311_u64,
+                self.schema.tag_ids => 511_u64,
+                self.schema.price => 100.01_f64,
+                self.schema.lat => 21.3069_f64,
+                self.schema.lon => -157.8583_f64,
+                self.schema.location_presence => 1_u64,
+                self.schema.positive_opinion_percent => 99_u64,
+                self.schema.date_created => DateTime::parse_from_rfc3339("2019-12-31T23:59:59-01:00").unwrap().with_timezone(&Utc),
+                self.schema.date_created_presence => 1_u64,
+            ),
+        )
+    }
+
+    fn commit(&mut self) -> Result<u64> {
         let commit_res = self.writer.commit();
         self.reader.reload()?;
         commit_res
@@ -89,19 +194,40 @@ impl ProductIndex {
 pub struct ProductSchema {
     pub schema: Schema,
     pub id: Field,
+    /// Has a value for every document with the `id` field
+    pub id_presence: Field,
     pub category_id: Field,
+    pub brand: Field,
+    pub category_path: Field,
     pub tag_ids: Field,
     pub price: Field,
     pub positive_opinion_percent: Field,
     pub attr_facets: Field,
     pub date_created: Field,
+    /// Has a value for every document with the `date_created` field
+    pub date_created_presence: Field,
+    pub lat: Field,
+    pub lon: Field,
+    /// Has a value for every document with the `lat` and `lon` fields
+    pub location_presence: Field,
+    pub product_id: Field,
+    /// Has a value for every document with the `product_id` field
+    pub product_id_presence: Field,
+    pub shop_id: Field,
+    pub shop_name: Field,
 }
 
 impl ProductSchema {
     pub fn create() -> Self {
         let mut schema = Schema::builder();
-        let id = schema.add_u64_field("id", INDEXED | STORED);
+        let id = schema.add_u64_field("id", INDEXED | STORED | FAST);
+        let id_presence = schema.add_u64_field(
+            "id_presence",
+            IntOptions::default().set_fast(Cardinality::MultiValues)
+        );
         let category_id = schema.add_u64_field("category_id", INDEXED | FAST);
+        let brand = schema.add_text_field("brand", STRING);
+        let category_path = schema.add_facet_field("category_path");
         let tag_ids = schema.add_u64_field(
             "tag_ids",
             IntOptions::default().set_fast(Cardinality::MultiValues)
@@ -115,15 +241,43 @@ impl ProductSchema {
             IntOptions::default().set_indexed().set_fast(Cardinality::MultiValues)
         );
         let date_created = schema.add_date_field("date_created", INDEXED | FAST);
+        let date_created_presence = schema.add_u64_field(
+            "date_created_presence",
+            IntOptions::default().set_fast(Cardinality::MultiValues)
+        );
+        let lat = schema.add_f64_field("lat", FAST);
+        let lon = schema.add_f64_field("lon", FAST);
+        let location_presence = schema.add_u64_field(
+            "location_presence",
+            IntOptions::default().set_fast(Cardinality::MultiValues)
+        );
+        let product_id = schema.add_u64_field("product_id", FAST);
+        let product_id_presence = schema.add_u64_field(
+            "product_id_presence",
+            IntOptions::default().set_fast(Cardinality::MultiValues)
+        );
+        let shop_id = schema.add_u64_field("shop_id", FAST);
+        let shop_name = schema.add_text_field("shop_name", STRING);
         Self {
             schema: schema.build(),
             id,
+            id_presence,
             category_id,
+            brand,
+            category_path,
             tag_ids,
             price,
             positive_opinion_percent,
             attr_facets,
             date_created,
+            date_created_presence,
+            lat,
+            lon,
+            location_presence,
+            product_id,
+            product_id_presence,
+            shop_id,
+            shop_name,
         }
     }
 }